@@ -11,6 +11,14 @@ const CONFIG = {
     CHAT_SPEED: 1 / 60.0,
     // Maximum amount of spacers to add (sets the maximum margin between messages).
     MAX_SPACERS: 10,
+    // Receive messages from yarrosco's websocket feed. If the page isn't served by
+    // yarrosco itself, it falls back to polling the DB files.
+    USE_WEBSOCKET: true,
+    // Time to wait before reconnecting to the websocket feed (milliseconds).
+    WS_RECONNECT_MS: 2000,
+    // Name of one of the [filters] of yarrosco's config, to show only what it
+    // matches. Also taken from the page address, e.g. yarrosco_chat.html?filter=chat
+    FILTER: new URLSearchParams(window.location.search).get("filter") ?? "",
     // Time between queries to yarrosco's DB to check new messages (milliseconds).
     DB_POLL_RATE_MS: 250,
     // Time between chat updates - basically to implement the CHAT_SPEED.
@@ -28,9 +36,14 @@ class Message {
         this.provider_name = msg.provider_name;
         this.room = msg.room;
         this.username = msg.username;
-        this.message = msg.message;
+        this.login = msg.author?.login ?? "";
+        this.color = msg.author?.color ?? "";
+        this.roles = msg.author?.roles ?? [];
+        this.message = msg.message ?? "";
         this.msgid = msg.msgid;
-        this.timestamp = msg.timestamp;
+        this.timestamp = toMillis(msg.timestamp);
+        this.kind = "message";
+        this.notice = "";
         this.badges = new Array();
         for (let b in msg.badges) {
             let badge = new Badge(msg.badges[b]);
@@ -41,6 +54,23 @@ class Message {
             let emote = new Emote(msg.emotes[e]);
             this.emotes.push(emote);
         }
+        this.bits = msg.bits ?? 0;
+        this.cheermotes = new Array();
+        for (let c in msg.cheermotes) {
+            let cheermote = new Cheermote(msg.cheermotes[c]);
+            this.cheermotes.push(cheermote);
+        }
+        this.fragments = new Array();
+        for (let f in msg.fragments) {
+            let fragment = new Fragment(msg.fragments[f]);
+            this.fragments.push(fragment);
+        }
+        this.reply_to = msg.reply_to ? new ReplyTo(msg.reply_to) : null;
+        this.reactions = new Array();
+        for (let r in msg.reactions) {
+            let reaction = new ReactionCount(msg.reactions[r]);
+            this.reactions.push(reaction);
+        }
     }
     provider_tag() {
         let tag = CONFIG.PROVIDER_TAG_MAP.get(this.provider_name);
@@ -69,12 +99,50 @@ class Emote {
         this.url = emote.url;
     }
 }
+class Cheermote {
+    constructor(cheermote) {
+        this.prefix = cheermote.prefix;
+        this.bits = cheermote.bits;
+        this.from = cheermote.from;
+        this.to = cheermote.to;
+        this.name = cheermote.name;
+        this.url = cheermote.url;
+        this.color = cheermote.color;
+    }
+}
+class Fragment {
+    constructor(fragment) {
+        this.type = fragment.type;
+        this.text = fragment.text;
+        this.url = fragment.url ?? "";
+        this.bits = fragment.bits ?? 0;
+        this.color = fragment.color ?? "";
+    }
+}
+class ReplyTo {
+    constructor(reply) {
+        this.msgid = reply.msgid;
+        this.username = reply.author?.display_name || reply.author?.login || "";
+        this.snippet = reply.snippet ?? "";
+    }
+}
+class ReactionCount {
+    constructor(reaction) {
+        this.key = reaction.key;
+        this.users = reaction.users ?? [];
+    }
+}
 var messages = new Map();
+// Reactions seen, by "provider|msgid", to undo them when they're deleted.
+var reactions = new Map();
 var last_hash = "";
 var last_linecount = 0;
 var last_html = "";
 var last_update_time = 0;
 var last_load_time = 0;
+var last_cursor = "";
+var feed_ever_connected = false;
+var poll_timer = undefined;
 const simpleHash = (str) => {
     let hash = 0;
     for (let i = 0; i < str.length; i++) {
@@ -111,17 +179,99 @@ const reqListener = (xhr) => {
     for (const n in lines) {
         let line = lines[n].trim();
         if (line) {
-            let obj = JSON.parse(line);
-            if (obj.Message) {
-                let msg = new Message(obj.Message);
-                let key = msg.key();
-                if (!messages.has(key)) {
-                    console.log(`#${msg.provider_name}::${msg.username}> ${msg.message}`);
-                    messages.set(key, msg);
-                }
+            addEvent(JSON.parse(line));
+        }
+    }
+    trimMessages();
+    updateChat();
+    return lines.length;
+};
+const addMessage = (msg) => {
+    let key = msg.key();
+    if (!messages.has(key)) {
+        console.log(`#${msg.provider_name}::${msg.username}> ${msg.notice} ${msg.message}`);
+        messages.set(key, msg);
+    }
+};
+const addEvent = (obj) => {
+    if (obj.Message) {
+        addMessage(new Message(obj.Message));
+    }
+    else if (obj.Subscription || obj.SubGift || obj.Raid || obj.Announcement) {
+        // Notices are shown among the messages, highlighted.
+        let kind = Object.keys(obj)[0];
+        let msg = new Message(obj[kind]);
+        msg.kind = kind.toLowerCase();
+        msg.notice = obj[kind].system_message ?? "";
+        addMessage(msg);
+    }
+    else if (obj.MessageEdited) {
+        let edit = obj.MessageEdited;
+        for (const msg of messages.values()) {
+            if (msg.provider_name == edit.provider_name && msg.msgid == edit.target_msgid) {
+                msg.message = edit.message;
+                msg.fragments = (edit.fragments ?? []).map((f) => new Fragment(f));
             }
         }
     }
+    else if (obj.Reaction) {
+        let reaction = obj.Reaction;
+        reactions.set(`${reaction.provider_name}|${reaction.msgid}`, reaction);
+        updateReaction(reaction, true);
+    }
+    else if (obj.MessageDeleted) {
+        let del = obj.MessageDeleted;
+        let reaction = reactions.get(`${del.provider_name}|${del.target_msgid}`);
+        if (reaction) {
+            reactions.delete(`${del.provider_name}|${del.target_msgid}`);
+            updateReaction(reaction, false);
+        }
+        removeMessages((msg) => msg.provider_name == del.provider_name && msg.msgid == del.target_msgid);
+    }
+    else if (obj.UserPurged) {
+        let purge = obj.UserPurged;
+        // Twitch sends the login, while messages carry the display name.
+        let username = purge.username.toLowerCase();
+        removeMessages((msg) => msg.provider_name == purge.provider_name && msg.room == purge.room
+            && (msg.login == purge.username || msg.username.toLowerCase() == username)
+            && msg.timestamp <= toMillis(purge.timestamp));
+    }
+    else if (obj.ChatCleared) {
+        let clear = obj.ChatCleared;
+        removeMessages((msg) => msg.provider_name == clear.provider_name && msg.room == clear.room
+            && msg.timestamp <= toMillis(clear.timestamp));
+    }
+};
+// Adds or takes back a reaction on the message it refers to. Messages loaded
+// from the database already count the reactions stored before them.
+const updateReaction = (reaction, add) => {
+    for (const msg of messages.values()) {
+        if (msg.provider_name != reaction.provider_name || msg.msgid != reaction.target_msgid) {
+            continue;
+        }
+        let count = msg.reactions.find((r) => r.key == reaction.key);
+        if (!count) {
+            count = new ReactionCount({ key: reaction.key });
+            msg.reactions.push(count);
+        }
+        let users = count.users.filter((user) => user != reaction.username);
+        if (add) {
+            users.push(reaction.username);
+        }
+        count.users = users;
+        msg.reactions = msg.reactions.filter((r) => r.users.length > 0);
+    }
+};
+// Takes messages off the chat after a deletion, a ban/timeout or a chat clear.
+const removeMessages = (matches) => {
+    for (const [key, msg] of messages) {
+        if (matches(msg)) {
+            console.log(`removed #${msg.provider_name}::${msg.username}> ${msg.message}`);
+            messages.delete(key);
+        }
+    }
+};
+const trimMessages = () => {
     let toDelete = messages.size - CONFIG.MAX_MESSAGES;
     if (toDelete > 0) {
         let keys = [...messages.keys()].sort().slice(0, toDelete);
@@ -129,12 +279,31 @@ const reqListener = (xhr) => {
             messages.delete(keys[i]);
         }
     }
-    updateChat();
-    return lines.length;
+};
+// Databases written by older versions of yarrosco have timestamps in seconds.
+const toMillis = (timestamp) => {
+    return timestamp < 1e11 ? timestamp * 1000 : timestamp;
 };
 const escapeHtml = (unsafe) => {
     return unsafe.replaceAll('&', '&amp;').replaceAll('<', '&lt;').replaceAll('>', '&gt;').replaceAll('"', '&quot;').replaceAll("'", '&#039;');
 };
+const renderFragments = (fragments) => {
+    return fragments.map((f) => {
+        let text = escapeHtml(f.text);
+        switch (f.type) {
+            case "emote":
+                return `<img src="${f.url}" alt="${text}" class="emote">`;
+            case "cheermote":
+                return `<img src="${f.url}" alt="${text}" class="cheermote"><span class="cheer-amount" style="color: ${f.color}">${f.bits}</span>`;
+            case "mention":
+                return `<span class="mention">${text}</span>`;
+            case "link":
+                return `<span class="link">${text}</span>`;
+            default:
+                return text;
+        }
+    }).join("");
+};
 var stringToColour = function (str) {
     var hash = 0;
     for (var i = 0; i < str.length; i++) {
@@ -162,7 +331,7 @@ const updateChat = () => {
             console.log(`missing data for key-${i} ${k}`);
             continue;
         }
-        if (msg.timestamp < first_timestamp) {
+        if (msg.timestamp / 1000 < first_timestamp) {
             continue;
         }
         let badges = "";
@@ -170,22 +339,58 @@ const updateChat = () => {
             let badge = msg.badges[i];
             badges += `<img src="${badge.url}" alt="${badge.name}" class="badge">`;
         }
-        let message = escapeHtml(msg.message);
-        for (let i in msg.emotes) {
-            // TODO: This code does not cut the emotes as specified and may result in undefined behavior.
-            let emote = msg.emotes[i];
-            let img = `<img src="${emote.url}" alt="${emote.name}" class="emote">`;
-            message = message.replaceAll(emote.name, img);
+        let message = "";
+        if (msg.fragments.length > 0) {
+            message = renderFragments(msg.fragments);
         }
-        let color = stringToColour(msg.username);
-        let text = `
-        <div class="shadow chatmsg chatmsg-${msg.provider_name}">
+        else {
+            // Messages stored by older versions of yarrosco have no fragments.
+            message = escapeHtml(msg.message);
+            if (msg.cheermotes.length > 0) {
+                // Cheers are whole words, so "Cheer1" doesn't replace part of "Cheer100".
+                let cheers = new Map(msg.cheermotes.map((c) => [c.name, c]));
+                message = message.split(" ").map((word) => {
+                    let cheer = cheers.get(word);
+                    if (!cheer) {
+                        return word;
+                    }
+                    return `<img src="${cheer.url}" alt="${cheer.name}" class="cheermote"><span class="cheer-amount" style="color: ${cheer.color}">${cheer.bits}</span>`;
+                }).join(" ");
+            }
+            for (let i in msg.emotes) {
+                // TODO: This code does not cut the emotes as specified and may result in undefined behavior.
+                let emote = msg.emotes[i];
+                let img = `<img src="${emote.url}" alt="${emote.name}" class="emote">`;
+                message = message.replaceAll(emote.name, img);
+            }
+        }
+        let color = msg.color || stringToColour(msg.username);
+        let roles = msg.roles.map((role) => ` role-${role}`).join("");
+        let notice = "";
+        if (msg.notice) {
+            notice = `<div class="notice">${escapeHtml(msg.notice)}</div>`;
+        }
+        let reply = "";
+        if (msg.reply_to) {
+            reply = `<div class="reply">↪ replying to ${escapeHtml(msg.reply_to.username)}: ${escapeHtml(msg.reply_to.snippet)}</div>`;
+        }
+        let reacts = "";
+        if (msg.reactions.length > 0) {
+            reacts = msg.reactions.map((r) => `<span class="reaction">${escapeHtml(r.key)} ${r.users.length}</span>`).join("");
+            reacts = `<div class="reactions">${reacts}</div>`;
+        }
+        let line = "";
+        if (msg.message || !msg.notice) {
+            line = `
             <div class="provider provider-${msg.provider_name}">${msg.provider_tag()}
             </div><div class="badges badges-${msg.provider_name}">${badges}</div><div class="username" style="color: ${color}">${msg.username}
-            </div><span class="separator">:</span><div class="message">${message}</div>
+            </div><span class="separator">:</span><div class="message">${message}</div>`;
+        }
+        let text = `
+        <div class="shadow chatmsg chatmsg-${msg.provider_name} chatmsg-${msg.kind}${roles}">${notice}${reply}${line}${reacts}
         </div>
         `;
-        let spacing = (msg.timestamp - last_timestamp) * CONFIG.CHAT_SPEED;
+        let spacing = (msg.timestamp / 1000 - last_timestamp) * CONFIG.CHAT_SPEED;
         let preftext = "";
         for (let i = 0; i < spacing && i < CONFIG.MAX_SPACERS; i++) {
             preftext += `<div class="spacing"></div>`;
@@ -194,7 +399,7 @@ const updateChat = () => {
             text = `<div class="spacing_group">${preftext}</div>` + text;
         }
         chatHTML += text;
-        last_timestamp = msg.timestamp;
+        last_timestamp = msg.timestamp / 1000;
     }
     let spacing = (timestamp - last_timestamp) * CONFIG.CHAT_SPEED;
     let preftext = "";
@@ -221,7 +426,7 @@ const loadData = () => {
     req.onload = (response) => {
         onLoadData(req);
     };
-    req.open("get", "yarrdb_data.jsonl", true);
+    req.open("get", withFilter("yarrdb_data.jsonl"), true);
     req.send();
 };
 const loadLog = () => {
@@ -234,15 +439,67 @@ const loadLog = () => {
     // .. ok, the problem is in the web server that it returns same E-Tag or modified dates.
     // .. we probably need a proper way to send data from a web backend.
     // .. this happens because the file is never closed until it checkpoints.
-    req.open("get", "yarrdb_log.jsonl?v=" + Math.random(), true);
+    req.open("get", withFilter("yarrdb_log.jsonl?v=" + Math.random()), true);
     // TODO: Also we froze firefox after a few hours of working. We need to debug this.
     // .. this seems because we keep updating the HTML in the background and Firefox delays
     // .. these until it wakes up. Or JS itself might be stopped.
     req.send();
 };
-window.onload = () => {
+// Only yarrosco applies the filter, other web servers ignore it.
+const withFilter = (url) => {
+    if (!CONFIG.FILTER) {
+        return url;
+    }
+    const sep = url.includes("?") ? "&" : "?";
+    return `${url}${sep}filter=${encodeURIComponent(CONFIG.FILTER)}`;
+};
+
+const startPolling = () => {
+    if (poll_timer !== undefined) {
+        return;
+    }
     loadData();
     loadLog();
+    poll_timer = window.setInterval(loadLog, CONFIG.DB_POLL_RATE_MS);
+};
+const connectFeed = () => {
+    const proto = window.location.protocol == "https:" ? "wss:" : "ws:";
+    let url = `${proto}//${window.location.host}/ws`;
+    if (last_cursor) {
+        // Resume from the last event seen, so nothing is lost while reconnecting.
+        url += `?since=${encodeURIComponent(last_cursor)}`;
+    }
+    url = withFilter(url);
+    const ws = new WebSocket(url);
+    ws.onopen = () => {
+        feed_ever_connected = true;
+    };
+    ws.onmessage = (ev) => {
+        let frame = JSON.parse(ev.data);
+        // Snapshots carry a list of events, the rest of frames carry just one.
+        let entries = frame.type == "snapshot" ? frame.events : [frame];
+        for (const entry of entries) {
+            addEvent(entry.event);
+            last_cursor = entry.cursor;
+        }
+        trimMessages();
+        updateChat();
+    };
+    ws.onclose = () => {
+        if (!feed_ever_connected) {
+            console.log("websocket feed not available, polling the database files instead");
+            startPolling();
+            return;
+        }
+        window.setTimeout(connectFeed, CONFIG.WS_RECONNECT_MS);
+    };
+};
+window.onload = () => {
+    if (CONFIG.USE_WEBSOCKET && window.location.protocol.startsWith("http")) {
+        connectFeed();
+    }
+    else {
+        startPolling();
+    }
 };
-window.setInterval(loadLog, CONFIG.DB_POLL_RATE_MS);
 window.setInterval(updateChat, 2 * CONFIG.CHAT_UPDATE_RATE_MS);
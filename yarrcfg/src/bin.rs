@@ -1,12 +1,21 @@
 use anyhow::Result;
-use yarrcfg::parse_config;
+use clap::Parser;
+use yarrcfg::{set_log_level, ConfigArgs, ConfigLoader};
+
+/// Prints the config as Yarrosco sees it, after replacing the secrets.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    cfg: ConfigArgs,
+}
+
 fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    set_log_level(args.cfg.log_level.as_deref());
     env_logger::init();
 
-    let cfg = parse_config()?;
+    let cfg = ConfigLoader::from_args(&args.cfg).load()?;
     println!("{:#?}", cfg);
     Ok(())
 }
@@ -0,0 +1,29 @@
+use clap::Args;
+use std::path::PathBuf;
+
+/// Command line options shared by every binary that reads the config.
+#[derive(Args, Debug, Clone)]
+pub struct ConfigArgs {
+    /// Main config file
+    #[clap(long, env = "YARROSCO_CONFIG", default_value = crate::CONFIG_FILE)]
+    pub config: PathBuf,
+    /// Secrets file [default: yarrsecrets.toml next to the config file]
+    #[clap(long, env = "YARROSCO_SECRETS")]
+    pub secrets: Option<PathBuf>,
+    /// Folder where relative paths in the config are resolved (database files,
+    /// assets_dir and sink outputs) [default: the folder of the config file]
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Log level (error, warn, info, debug, trace). Overrides RUST_LOG
+    #[clap(long)]
+    pub log_level: Option<String>,
+}
+
+impl ConfigArgs {
+    pub fn secrets_path(&self) -> PathBuf {
+        match &self.secrets {
+            Some(path) => path.clone(),
+            None => self.config.with_file_name(crate::SECRETS_FILE),
+        }
+    }
+}
@@ -1,33 +1,264 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, warn};
 use serde_derive::Deserialize;
 use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::thread;
 use thiserror::Error;
+use yarrdata::router::Filter;
 use yarrpass::{password, SaltAndCipher};
 
+mod cli;
+pub use cli::ConfigArgs;
+pub use yarrcommon::set_log_level;
+
 #[derive(Error, Debug)]
 pub enum CfgError {
     #[error("Invalid port number {0:?}")]
     InvalidPortNumber(String),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub logfile: String,
     pub checkpointfile: String,
+    #[serde(default)]
     pub twitch: BTreeMap<String, Twitch>,
+    #[serde(default)]
     pub matrix: BTreeMap<String, Matrix>,
+    /// Made-up chats, to work on the overlay without a live one.
+    #[serde(default)]
+    pub demo: BTreeMap<String, Demo>,
+    /// Recorded sessions published again.
+    #[serde(default)]
+    pub replay: BTreeMap<String, Replay>,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default = "Config::default_sinks")]
+    pub sinks: BTreeMap<String, Sink>,
+    /// Named filters, used by the sinks, the overlay and the renderers.
+    #[serde(default)]
+    pub filters: BTreeMap<String, Filter>,
 }
 
-#[derive(Deserialize, Debug)]
+impl Config {
+    /// Makes the relative file paths relative to `dir` instead of the current folder.
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        resolve_path(dir, &mut self.logfile);
+        resolve_path(dir, &mut self.checkpointfile);
+        resolve_path(dir, &mut self.http.assets_dir);
+        for replay in self.replay.values_mut() {
+            resolve_path(dir, &mut replay.path);
+        }
+        for sink in self.sinks.values_mut() {
+            match &mut sink.kind {
+                SinkKind::Stdout => {}
+                SinkKind::Jsonl { path } | SinkKind::ObsText { path, .. } => {
+                    resolve_path(dir, path)
+                }
+            }
+        }
+    }
+    /// Without a `[sinks]` section, events are printed to stdout as always.
+    fn default_sinks() -> BTreeMap<String, Sink> {
+        let stdout = Sink {
+            enabled: true,
+            kind: SinkKind::Stdout,
+            filter: None,
+        };
+        BTreeMap::from([("stdout".to_owned(), stdout)])
+    }
+    /// The filter with the given name, or one that lets everything through if
+    /// no name is given.
+    pub fn filter(&self, name: Option<&str>) -> Result<Filter> {
+        match name {
+            None => Ok(Filter::default()),
+            Some(name) => self
+                .filters
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("there's no [filters.{}] in the config", name)),
+        }
+    }
+    /// Checks that every filter referenced exists, and the values that can't
+    /// be checked while parsing.
+    fn validate(&self) -> Result<()> {
+        for (name, sink) in self.sinks.iter() {
+            if let Err(e) = self.filter(sink.filter.as_deref()) {
+                bail!("[sinks.{}]: {}", name, e);
+            }
+        }
+        for (name, demo) in self.demo.iter() {
+            if !Demo::RATES.contains(&demo.rate) {
+                bail!(
+                    "[demo.{}]: rate has to be between {} and {} events per second",
+                    name,
+                    Demo::RATES.start(),
+                    Demo::RATES.end()
+                );
+            }
+        }
+        for (name, replay) in self.replay.iter() {
+            if !Replay::SPEEDS.contains(&replay.speed) {
+                bail!(
+                    "[replay.{}]: speed has to be between {} and {}",
+                    name,
+                    Replay::SPEEDS.start(),
+                    Replay::SPEEDS.end()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve_path(dir: &Path, path: &mut String) {
+    if Path::new(path.as_str()).is_relative() {
+        *path = dir.join(path.as_str()).to_string_lossy().into_owned();
+    }
+}
+
+/// An output that receives every event accepted into the database.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Sink {
+    #[serde(default = "Sink::default_enabled")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Name of one of the `[filters]`, to receive only the events it matches.
+    #[serde(default)]
+    pub filter: Option<String>,
+}
+
+impl Sink {
+    fn default_enabled() -> bool {
+        true
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Prints every event to the standard output.
+    Stdout,
+    /// Appends every event as a JSON line to a file.
+    Jsonl { path: String },
+    /// Keeps a text file with the last messages, to be used as an OBS text source.
+    ObsText {
+        path: String,
+        #[serde(default = "SinkKind::default_max_lines")]
+        max_lines: usize,
+    },
+}
+
+impl SinkKind {
+    fn default_max_lines() -> usize {
+        10
+    }
+}
+
+/// Built-in web server that serves the overlay and the database contents.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Http {
+    #[serde(default = "Http::default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "Http::default_bind")]
+    pub bind: String,
+    #[serde(default = "Http::default_port")]
+    pub port: u16,
+    /// Folder with the HTML overlay (the `data/` folder of this repository).
+    #[serde(default = "Http::default_assets_dir")]
+    pub assets_dir: String,
+}
+
+impl Http {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_bind() -> String {
+        "127.0.0.1".to_owned()
+    }
+    fn default_port() -> u16 {
+        8080
+    }
+    fn default_assets_dir() -> String {
+        "data".to_owned()
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            bind: Self::default_bind(),
+            port: Self::default_port(),
+            assets_dir: Self::default_assets_dir(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Matrix {
     pub user_id: String,
     pub access_token: SecString,
     pub room_id: String,
 }
 
-#[derive(Deserialize, Debug)]
+/// Publishes random messages, subs, raids and deletions, like a busy chat.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Demo {
+    /// Events per second, on average.
+    #[serde(default = "Demo::default_rate")]
+    pub rate: f64,
+    /// Makes the same session on every run. Without it, every run is different.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default = "Demo::default_room")]
+    pub room: String,
+    /// Provider of the events. Setting "twitch" shows the Twitch logo in the overlay.
+    #[serde(default = "Demo::default_provider_name")]
+    pub provider_name: String,
+}
+
+impl Demo {
+    /// Events per second accepted for `rate`.
+    pub const RATES: RangeInclusive<f64> = 0.001..=1000.0;
+
+    fn default_rate() -> f64 {
+        1.0
+    }
+    fn default_room() -> String {
+        "#demo".to_owned()
+    }
+    fn default_provider_name() -> String {
+        "demo".to_owned()
+    }
+}
+
+/// Publishes the events of a JSONL file again, keeping the time between them.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Replay {
+    /// A database file, `logfile` or `checkpointfile`, or any file in that format.
+    pub path: String,
+    /// How many times faster than recorded, e.g. 0.5 plays at half the speed.
+    #[serde(default = "Replay::default_speed")]
+    pub speed: f64,
+    /// Gives the events the time they're published instead of the recorded one.
+    #[serde(default)]
+    pub rewrite_timestamps: bool,
+}
+
+impl Replay {
+    /// Values accepted for `speed`.
+    pub const SPEEDS: RangeInclusive<f64> = 0.001..=1000.0;
+
+    fn default_speed() -> f64 {
+        1.0
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Twitch {
     pub hostname: String,
     pub username: String,
@@ -73,7 +304,7 @@ pub struct SecReplace {
 }
 
 /// SecString is basically a string that doesn't have debug output by default.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct SecString(pub String);
 
 impl<'de> serde::Deserialize<'de> for SecString {
@@ -94,88 +325,152 @@ impl std::fmt::Debug for SecString {
     }
 }
 
+/// Default secrets file, next to the config file.
+pub const SECRETS_FILE: &str = "yarrsecrets.toml";
+/// Default config file, in the current working directory.
+pub const CONFIG_FILE: &str = "yarrosco.toml";
+
+/// Reads the default config files, or the one in `YARROSCO_CONFIG` if set.
 pub fn parse_config() -> Result<Config> {
-    use std::fs::File;
-    use std::io::prelude::*;
-    use std::path::Path;
-    let path = Path::new("yarrsecrets.toml");
-    let display = path.display();
-    let mut file = File::open(&path).with_context(|| format!("couldn't open {}", display))?;
-    let mut s = String::new();
-    file.read_to_string(&mut s)
-        .with_context(|| format!("couldn't read {}", display))?;
-
-    let cfg: SecConfig =
-        toml::from_str(&s).with_context(|| format!("couldn't parse {}", display))?;
-    // -- replace all secrets --
-    let mut secrets: Vec<SecReplace> = vec![];
-    let pass = if cfg.secrets.is_empty() {
-        vec![]
-    } else {
-        password()?
-    };
-    let mut handles = vec![];
-    for (name, v) in cfg.secrets.iter() {
-        let name = name.clone();
-        let v = v.clone();
-        let pass = pass.clone();
-        if v.version != 1 {
-            warn!("Unsupported secret version <{}>", v.version);
+    ConfigLoader::new().load()
+}
+
+/// Reads the config files. The passphrase is remembered after the first load,
+/// so the config can be loaded again without asking for it.
+pub struct ConfigLoader {
+    config_path: PathBuf,
+    secrets_path: PathBuf,
+    data_dir: Option<PathBuf>,
+    pass: Option<Vec<u8>>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        let config_path = std::env::var_os("YARROSCO_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+        Self {
+            secrets_path: config_path.with_file_name(SECRETS_FILE),
+            config_path,
+            data_dir: None,
+            pass: None,
+        }
+    }
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn from_args(args: &ConfigArgs) -> Self {
+        Self {
+            config_path: args.config.clone(),
+            secrets_path: args.secrets_path(),
+            data_dir: args.data_dir.clone(),
+            pass: None,
         }
-        let handle = thread::spawn(move || -> Result<SecReplace> {
-            let sac = SaltAndCipher::deserialize(&v.secret.0).with_context(|| {
-                format!(
-                    "while processing secret for {:?}, placeholder {:?}",
-                    name, v.placeholder
-                )
-            })?;
-            let secret = sac.decrypt(&pass)?;
-            let s = SecReplace {
-                name: name.to_string(),
-                placeholder: v.placeholder,
-                secret: SecString(secret),
-                use_count: 0,
-            };
-            Ok(s)
-        });
-        handles.push(handle);
     }
-    for handle in handles {
-        let s = handle
-            .join()
-            .expect("error in thread while decoding secrets")?;
-        secrets.push(s);
+    /// Files read by `load`, to watch them for changes.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        vec![self.secrets_path.clone(), self.config_path.clone()]
+    }
+    /// Folder where relative paths inside the config are resolved.
+    pub fn data_dir(&self) -> &Path {
+        match &self.data_dir {
+            Some(dir) => dir,
+            None => self.config_path.parent().unwrap_or_else(|| Path::new("")),
+        }
     }
+    pub fn load(&mut self) -> Result<Config> {
+        use std::fs::File;
+        use std::io::prelude::*;
+        let path = self.secrets_path.as_path();
+        let display = path.display();
+        let mut file = File::open(&path).with_context(|| format!("couldn't open {}", display))?;
+        let mut s = String::new();
+        file.read_to_string(&mut s)
+            .with_context(|| format!("couldn't read {}", display))?;
 
-    let path = Path::new("yarrosco.toml");
-    let display = path.display();
+        let cfg: SecConfig =
+            toml::from_str(&s).with_context(|| format!("couldn't parse {}", display))?;
+        // -- replace all secrets --
+        let mut secrets: Vec<SecReplace> = vec![];
+        let pass = match &self.pass {
+            _ if cfg.secrets.is_empty() => vec![],
+            Some(pass) => pass.clone(),
+            None => {
+                let pass = password()?;
+                self.pass = Some(pass.clone());
+                pass
+            }
+        };
+        let mut handles = vec![];
+        for (name, v) in cfg.secrets.iter() {
+            let name = name.clone();
+            let v = v.clone();
+            let pass = pass.clone();
+            if v.version != 1 {
+                warn!("Unsupported secret version <{}>", v.version);
+            }
+            let handle = thread::spawn(move || -> Result<SecReplace> {
+                let sac = SaltAndCipher::deserialize(&v.secret.0).with_context(|| {
+                    format!(
+                        "while processing secret for {:?}, placeholder {:?}",
+                        name, v.placeholder
+                    )
+                })?;
+                let secret = sac.decrypt(&pass)?;
+                let s = SecReplace {
+                    name: name.to_string(),
+                    placeholder: v.placeholder,
+                    secret: SecString(secret),
+                    use_count: 0,
+                };
+                Ok(s)
+            });
+            handles.push(handle);
+        }
+        for handle in handles {
+            let s = handle
+                .join()
+                .expect("error in thread while decoding secrets")?;
+            secrets.push(s);
+        }
 
-    let mut file = File::open(&path).with_context(|| format!("couldn't open {}", display))?;
+        let path = self.config_path.as_path();
+        let display = path.display();
 
-    let mut s = String::new();
-    file.read_to_string(&mut s)
-        .with_context(|| format!("couldn't read {}", display))?;
+        let mut file = File::open(&path).with_context(|| format!("couldn't open {}", display))?;
 
-    for secret in secrets.iter_mut() {
-        let count = s.matches(&secret.placeholder).count();
-        if count == 0 {
-            warn!(
-                "secret {:?} unused in config, {:?} not found in the text",
-                &secret.name, &secret.placeholder
-            );
-            continue;
-        }
-        s = s.replace(&secret.placeholder, &secret.secret.0);
-        let zcount = s.matches(&secret.placeholder).count();
-        if zcount != 0 {
-            warn!(
-                "not all occurences of {} where replaced. {} references left.",
-                &secret.placeholder, zcount
-            );
+        let mut s = String::new();
+        file.read_to_string(&mut s)
+            .with_context(|| format!("couldn't read {}", display))?;
+
+        for secret in secrets.iter_mut() {
+            let count = s.matches(&secret.placeholder).count();
+            if count == 0 {
+                warn!(
+                    "secret {:?} unused in config, {:?} not found in the text",
+                    &secret.name, &secret.placeholder
+                );
+                continue;
+            }
+            s = s.replace(&secret.placeholder, &secret.secret.0);
+            let zcount = s.matches(&secret.placeholder).count();
+            if zcount != 0 {
+                warn!(
+                    "not all occurences of {} where replaced. {} references left.",
+                    &secret.placeholder, zcount
+                );
+            }
+            secret.use_count = count - zcount;
+            debug!("Used secret {:?} {} times", &secret.name, secret.use_count)
         }
-        secret.use_count = count - zcount;
-        debug!("Used secret {:?} {} times", &secret.name, secret.use_count)
+        let mut cfg: Config =
+            toml::from_str(&s).with_context(|| format!("couldn't parse {}", display))?;
+        cfg.resolve_paths(self.data_dir());
+        cfg.validate()
+            .with_context(|| format!("invalid config in {}", display))?;
+        Ok(cfg)
     }
-    let cfg: Config = toml::from_str(&s).with_context(|| format!("couldn't parse {}", display))?;
-    Ok(cfg)
 }
@@ -0,0 +1,11 @@
+//! Small helpers shared by every binary of the workspace. It has no
+//! dependencies, so any crate can use it.
+
+/// Sets up RUST_LOG for env_logger: the given level wins, then RUST_LOG, then INFO.
+pub fn set_log_level(level: Option<&str>) {
+    if let Some(level) = level {
+        std::env::set_var("RUST_LOG", level);
+    } else if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "INFO");
+    }
+}
@@ -1,49 +1,60 @@
-use std::time::SystemTime;
-
 use anyhow::Result;
-use futures::executor::block_on;
-use futures::future::FutureExt;
-use futures::StreamExt;
+use clap::Parser;
+use std::time::Duration;
 use yarrdata::{Event, Message, ProviderQueue};
-fn main() -> Result<()> {
-    // TODO: Move initizalization to yarrcommon
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+
+/// Publishes a few test events through a provider queue and prints what the
+/// subscriber gets.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    /// Provider name used for the test events
+    #[clap(long, default_value = "test-irc")]
+    provider: String,
+    /// Log level (error, warn, info, debug, trace). Overrides RUST_LOG
+    #[clap(long)]
+    log_level: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    yarrcommon::set_log_level(args.log_level.as_deref());
     env_logger::init();
-    // --
     let mut p = ProviderQueue::new("test-provider".to_owned());
     let e = Event::Message(Message {
-        provider_name: "test-irc".to_owned(),
+        provider_name: args.provider,
         room: "#test".to_owned(),
         message: "todo!()".to_owned(),
         username: "myself".to_owned(),
         msgid: "1234".to_owned(),
-        timestamp: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
+        timestamp: yarrdata::default_timestamp_ms(),
         ..Default::default()
     });
-    p.publish_sync(e.clone())?;
-    p.publish_sync(e.clone())?;
+    // The events published before subscribing wait in the queue.
+    p.publish(e.clone()).await?;
     let mut s = p.subscribe();
-    let ms = &mut s;
-    block_on(async move {
-        dbg!(ms.next().await);
-        // Blocks until the next message is ready.
-        dbg!(ms.next().await);
-    });
-    let ms = &mut s;
-    // This one doesn't block, but consumes the item. .peekable() can be used
-    // if we don't want to consume.
-    dbg!(ms.next().now_or_never());
-    p.publish_sync(e)?;
-    dbg!(ms.next().now_or_never());
-    dbg!(ms.next().now_or_never());
-    p.close_sync()?;
-    // Now it returns Some(None) from here on to signal the publisher has closed the stream.
-    dbg!(ms.next().now_or_never());
-    dbg!(ms.next().now_or_never());
+    p.publish(e.clone()).await?;
+    p.publish(e.clone()).await?;
+    dbg!(s.recv().await);
+    // This one doesn't wait, and gives None if nothing is ready.
+    dbg!(s.try_recv());
+    dbg!(s.try_recv());
+    dbg!(s.try_recv());
+    // Once the queue is full, publishing waits for the subscriber.
+    let mut small = ProviderQueue::with_capacity("test-provider".to_owned(), 2);
+    let mut slow = small.subscribe();
+    for _ in 0..2 {
+        small.publish(e.clone()).await?;
+    }
+    let full = tokio::time::timeout(Duration::from_millis(100), small.publish(e.clone())).await;
+    dbg!(full.is_err());
+    dbg!(slow.recv().await);
+    small.publish(e.clone()).await?;
+    small.close();
+    p.close();
+    // Now it returns Closed from here on to signal the publisher has closed the queue.
+    dbg!(s.recv().await);
+    dbg!(s.recv().await);
     Ok(())
 }
@@ -1,11 +1,19 @@
 extern crate tokio;
-use crate::default_timestamp;
+use crate::default_timestamp_ms;
+use crate::schema;
 use crate::Event;
-use anyhow::Result;
+use crate::ReactionCount;
+use anyhow::{bail, Result};
 use log::error;
 use log::info;
+use log::warn;
+use serde::Deserialize;
 use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use tokio::fs::File;
 use tokio::io::AsyncBufReadExt;
@@ -18,6 +26,8 @@ pub enum MessageIgnored {
     None,
     TooOld,
     Duplicated,
+    /// The message was already removed by a stored deletion, purge or clear.
+    Deleted,
 }
 
 #[derive(Debug, Clone)]
@@ -42,17 +52,111 @@ pub struct EventId {
 }
 
 impl EventId {
+    /// Opaque, URL-safe representation that clients can hand back to resume reading.
+    /// The fields are a JSON array inside, so any character can be in them.
+    pub fn to_cursor(&self) -> String {
+        let fields = (self.timestamp, &self.provider_name, &self.msgid);
+        // Can't fail, there are only strings and numbers.
+        hex::encode(serde_json::to_string(&fields).unwrap())
+    }
+    pub fn from_cursor(cursor: &str) -> Result<Self> {
+        let fields = hex::decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<(u64, String, String)>(&json).ok());
+        let (timestamp, provider_name, msgid) = match fields {
+            Some(fields) => fields,
+            None => bail!("invalid cursor {:?}", cursor),
+        };
+        Ok(Self {
+            timestamp,
+            provider_name,
+            msgid,
+        })
+    }
     pub fn from_event(event: &Event) -> Self {
-        match event {
-            Event::Message(msg) => Self {
-                timestamp: msg.timestamp,
-                provider_name: msg.provider_name.clone(),
-                msgid: msg.msgid.clone(),
-            },
+        Self {
+            timestamp: event.timestamp(),
+            provider_name: event.provider_name().to_owned(),
+            msgid: event.msgid().to_owned(),
+        }
+    }
+    /// The message a reply answers to, once the database found when it was sent.
+    pub fn reply_parent(msg: &crate::Message) -> Option<Self> {
+        let reply = msg.reply_to.as_ref()?;
+        Some(Self {
+            timestamp: reply.timestamp?,
+            provider_name: msg.provider_name.clone(),
+            msgid: reply.msgid.clone(),
+        })
+    }
+}
+
+/// Filters for `Log::query`. Every field is optional; strings must match exactly.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Query {
+    /// Only return events stored after this cursor (see `EventId::to_cursor`).
+    pub cursor: Option<String>,
+    /// Only return events with a timestamp greater or equal to this one, in milliseconds.
+    pub from_ts: Option<u64>,
+    /// Only return events with a timestamp lower than this one, in milliseconds.
+    pub to_ts: Option<u64>,
+    pub provider: Option<String>,
+    pub room: Option<String>,
+    pub username: Option<String>,
+    /// Id of the author in the provider (see `Author::id`).
+    pub user_id: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    pub const DEFAULT_LIMIT: usize = 100;
+    pub const MAX_LIMIT: usize = 1000;
+
+    pub fn matches(&self, event: &Event) -> bool {
+        let ts = event.timestamp();
+        if matches!(self.from_ts, Some(from) if ts < from)
+            || matches!(self.to_ts, Some(to) if ts >= to)
+        {
+            return false;
         }
+        field_matches(&self.provider, event.provider_name())
+            && field_matches(&self.room, event.room())
+            && field_matches(&self.username, event.username())
+            && field_matches(&self.user_id, event.author().map_or("", |a| &a.id))
+    }
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
     }
 }
 
+fn field_matches(filter: &Option<String>, value: &str) -> bool {
+    match filter {
+        Some(f) => f == value,
+        None => true,
+    }
+}
+
+/// One page of results from `Log::query`.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub events: Vec<(EventId, Event)>,
+    /// Where to continue reading from. It's the last event returned, or the
+    /// cursor given if nothing matched, so it can be used to poll for new events.
+    pub next_cursor: Option<EventId>,
+    /// True if there are more matching events after `next_cursor` right now.
+    pub has_more: bool,
+}
+
+/// Totals about the checkpoints performed, for monitoring.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckpointStats {
+    pub count: u64,
+    pub total_duration: Duration,
+    pub last_duration: Duration,
+}
+
 #[derive(Debug)]
 pub struct Log {
     maxsize: usize,
@@ -61,11 +165,26 @@ pub struct Log {
     last_checkpoint: SystemTime,
     log_lines: usize,
     log_writer: Option<BufWriter<File>>,
+    checkpoint_stats: CheckpointStats,
+    /// Schema version of the first event loaded in another version, if any.
+    /// The next checkpoint rewrites them in the current one.
+    migrated_from: Option<u64>,
+    /// Messages and the other events shown in the chat, up to `maxsize`.
     pub data: BTreeMap<EventId, CachedEvent>,
+    /// Deletions, purges and clears. They don't take the place of messages,
+    /// and are kept while they can still apply, see `prune`.
+    removals: BTreeMap<EventId, CachedEvent>,
+    /// Reactions, counted in their message. They don't take the place of
+    /// messages either, and go away with the message they react to.
+    reactions: BTreeMap<EventId, CachedEvent>,
+    /// Edits, applied to their message. Like reactions, they go away with it.
+    edits: BTreeMap<EventId, CachedEvent>,
 }
 
 impl Log {
     const MAX_DURATION: Duration = Duration::from_secs(60);
+    /// Removals, reactions or edits kept per slot of `maxsize`, in case of a flood.
+    const EXTRA_PER_SLOT: usize = 10;
     pub fn new(maxsize: usize, log_path: String, checkpoint_path: String) -> Self {
         Self {
             maxsize,
@@ -74,7 +193,12 @@ impl Log {
             log_lines: 0,
             log_writer: None,
             last_checkpoint: SystemTime::now(),
+            checkpoint_stats: CheckpointStats::default(),
+            migrated_from: None,
             data: BTreeMap::new(),
+            removals: BTreeMap::new(),
+            reactions: BTreeMap::new(),
+            edits: BTreeMap::new(),
         }
     }
     pub async fn load(&mut self) -> Result<()> {
@@ -91,17 +215,24 @@ impl Log {
                 let mut lines = my_buf_read.lines();
 
                 while let Some(line) = lines.next_line().await? {
-                    match Event::from_json(&line) {
-                        Ok(m) => {
+                    match schema::from_json(&line) {
+                        Ok((m, version)) => {
+                            self.note_version(version);
                             if let Err(e) = self.push_int(m) {
                                 error!("error while writing to database: {:?}", e);
                             }
                         }
-                        Err(e) => error!(
-                            "error while parsing JSON from checkpoint file: {:?}\n\
-                        original line: {:?}",
-                            e, line
-                        ),
+                        Err(e) => {
+                            error!(
+                                "error while parsing JSON from checkpoint file: {:?}\n\
+                            original line: {:?}",
+                                e, line
+                            );
+                            // Lines from newer versions are kept in the backup.
+                            if let Some(version) = schema::version(&line) {
+                                self.note_version(version);
+                            }
+                        }
                     }
                     self.log_lines += 1;
                 }
@@ -116,50 +247,154 @@ impl Log {
                 let mut lines = my_buf_read.lines();
 
                 while let Some(line) = lines.next_line().await? {
-                    match Event::from_json(&line) {
-                        Ok(m) => {
+                    match schema::from_json(&line) {
+                        Ok((m, version)) => {
+                            self.note_version(version);
                             if let Err(e) = self.push_int(m) {
                                 error!("error while writing to database: {:?}", e);
                             }
                         }
-                        Err(e) => error!(
-                            "error while parsing JSON from log file: {:?}\n\
-                        original line: {:?}",
-                            e, line
-                        ),
+                        Err(e) => {
+                            error!(
+                                "error while parsing JSON from log file: {:?}\n\
+                            original line: {:?}",
+                                e, line
+                            );
+                            // Lines from newer versions are kept in the backup.
+                            if let Some(version) = schema::version(&line) {
+                                self.note_version(version);
+                            }
+                        }
                     }
                 }
             }
         }
         Ok(())
     }
+    fn note_version(&mut self, version: u64) {
+        if version == schema::VERSION || self.migrated_from.is_some() {
+            return;
+        }
+        if version > schema::VERSION {
+            warn!(
+                "the database was written by a newer version of yarrosco (schema v{}), \
+                what this version doesn't know will be dropped",
+                version
+            );
+        } else {
+            info!(
+                "the database is in schema v{}, it will be upgraded to v{}",
+                version,
+                schema::VERSION
+            );
+        }
+        self.migrated_from = Some(version);
+    }
     pub async fn perform_checkpoint(&mut self) -> Result<()> {
+        let started = Instant::now();
         self.log_writer.take();
+        if let Some(version) = self.migrated_from.take() {
+            self.backup_before_migration(version).await?;
+        }
 
         {
             let mut writer = BufWriter::new(File::create(self.checkpoint_path.clone()).await?);
-            for (_, ce) in self.data.iter() {
+            for (_, ce) in self.events_after(None) {
                 writer.write_all(ce.json.as_bytes()).await?;
             }
             writer.flush().await?;
         } // ensure writer is closed at this point.
-        self.log_lines = 0;
         let mut writer = BufWriter::new(File::create(self.log_path.clone()).await?);
         // Keep 10 seconds or 5 messages.
-        let ts_from = default_timestamp() - 10;
-        let first_msg = self.data.len() - 5;
-        for (n, (_, ce)) in self.data.iter().enumerate() {
+        let ts_from = default_timestamp_ms() - 10_000;
+        let stored: Vec<_> = self.events_after(None).collect();
+        let first_msg = stored.len().saturating_sub(5);
+        let mut log_lines = 0;
+        for (n, (_, ce)) in stored.into_iter().enumerate() {
             if n < first_msg && ce.event.timestamp() < ts_from {
                 continue;
             }
             writer.write_all(ce.json.as_bytes()).await?;
-            self.log_lines += 1;
+            log_lines += 1;
         }
+        self.log_lines = log_lines;
         writer.flush().await?;
         self.log_writer = Some(writer);
         self.last_checkpoint = SystemTime::now();
+        let stats = &mut self.checkpoint_stats;
+        stats.count += 1;
+        stats.last_duration = started.elapsed();
+        stats.total_duration += stats.last_duration;
+        Ok(())
+    }
+    /// Keeps a copy of the files as they were before rewriting them in the
+    /// current schema, next to them with a ".v<version>.bak" suffix.
+    async fn backup_before_migration(&self, version: u64) -> Result<()> {
+        for path in [&self.checkpoint_path, &self.log_path] {
+            let backup = format!("{}.v{}.bak", path, version);
+            match tokio::fs::copy(path, &backup).await {
+                Ok(_) => info!("saved a copy of {:?} as {:?}", path, backup),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => bail!("couldn't back up {:?} before migrating it: {:?}", path, e),
+            }
+        }
         Ok(())
     }
+    pub fn checkpoint_stats(&self) -> CheckpointStats {
+        self.checkpoint_stats
+    }
+    /// Returns the events held in memory in the same JSONL format as the files.
+    pub fn to_jsonl(&self) -> String {
+        self.events_after(None)
+            .map(|(_, ce)| ce.json.as_str())
+            .collect()
+    }
+    /// Iterates in time order over the events stored after the given one, or
+    /// over everything if there's no cursor. Includes the removals, so readers
+    /// that already show a message learn that it's gone.
+    pub fn events_after(
+        &self,
+        cursor: Option<&EventId>,
+    ) -> impl Iterator<Item = (&EventId, &CachedEvent)> {
+        let start = match cursor {
+            Some(id) => Bound::Excluded(id.clone()),
+            None => Bound::Unbounded,
+        };
+        let range = (start, Bound::Unbounded);
+        let mut events: Vec<_> = self
+            .data
+            .range(range.clone())
+            .chain(self.removals.range(range.clone()))
+            .chain(self.reactions.range(range.clone()))
+            .chain(self.edits.range(range))
+            .collect();
+        events.sort_by_key(|(id, _)| *id);
+        events.into_iter()
+    }
+    /// Returns the events matching the query in time order, paginated by cursor.
+    pub fn query(&self, query: &Query) -> Result<Page> {
+        let cursor = query
+            .cursor
+            .as_deref()
+            .map(EventId::from_cursor)
+            .transpose()?;
+        let limit = query.limit();
+        let mut matching = self
+            .events_after(cursor.as_ref())
+            .filter(|(_, ce)| query.matches(&ce.event));
+        let events: Vec<(EventId, Event)> = matching
+            .by_ref()
+            .take(limit)
+            .map(|(id, ce)| (id.clone(), ce.event.clone()))
+            .collect();
+        let has_more = matching.next().is_some();
+        let next_cursor = events.last().map(|(id, _)| id.clone()).or(cursor);
+        Ok(Page {
+            events,
+            next_cursor,
+            has_more,
+        })
+    }
     async fn get_writer(&mut self) -> Result<&mut BufWriter<File>> {
         if self.log_writer.is_none() {
             self.perform_checkpoint().await?;
@@ -169,7 +404,8 @@ impl Log {
     async fn log(&mut self, json: String) -> Result<()> {
         let writer = self.get_writer().await?;
         writer.write_all(json.as_bytes()).await?;
-        // Seems flush is needed or on program stop wouldn't write.
+        // Flushing every line keeps the file current for web servers reading it.
+        // On shutdown the daemon performs a final checkpoint anyway.
         writer.flush().await?;
         self.log_lines += 1;
         let elapsed = self.last_checkpoint.elapsed().unwrap_or_default();
@@ -185,40 +421,333 @@ impl Log {
         }
         Ok(())
     }
-    fn push_int(&mut self, event: Event) -> Result<()> {
+    /// Whether a stored deletion, purge or clear covers this message or reaction.
+    ///
+    /// Removals are kept in the database, so a message seen again (e.g. the
+    /// provider sends it again after reconnecting) stays removed.
+    fn is_removed(&self, event: &Event) -> bool {
+        match event {
+            Event::Message(msg) => self.removals.values().any(|ce| ce.event.removes(msg)),
+            Event::Reaction(r) => self
+                .removals
+                .values()
+                .any(|ce| ce.event.removes_reaction(r)),
+            _ => false,
+        }
+    }
+    /// Whether an event is older than everything stored while the database
+    /// is full, so it would be the first one dropped.
+    fn is_too_old(&self, event: &Event) -> bool {
+        match self.data.keys().next() {
+            Some(first) => self.data.len() >= self.maxsize && first.timestamp > event.timestamp(),
+            None => false,
+        }
+    }
+    /// Forgets the removals, reactions and edits that can't apply anymore: once the
+    /// database is full, messages older than the oldest one stored are
+    /// ignored anyway.
+    fn prune(&mut self) {
+        let oldest = match self.data.keys().next() {
+            Some(first) if self.data.len() >= self.maxsize => Some(first.timestamp),
+            _ => None,
+        };
+        let cap = self.maxsize * Self::EXTRA_PER_SLOT;
+        for extra in [&mut self.removals, &mut self.reactions, &mut self.edits] {
+            if let Some(oldest) = oldest {
+                extra.retain(|id, _| id.timestamp >= oldest);
+            }
+            while extra.len() > cap {
+                let first = extra.keys().next().unwrap().clone();
+                extra.remove(&first);
+            }
+        }
+    }
+    /// Makes room for a new message, with the reactions and edits of the oldest one.
+    fn evict_oldest(&mut self) {
+        let first = self.data.keys().next().unwrap().clone();
+        if let Some(CachedEvent {
+            event: Event::Message(msg),
+            ..
+        }) = self.data.remove(&first)
+        {
+            self.reactions.retain(|_, ce| !refers_to(&ce.event, &msg));
+            self.edits.retain(|_, ce| !refers_to(&ce.event, &msg));
+        }
+    }
+    /// Drops the messages, their edits and the reactions that a deletion, purge
+    /// or clear refers to.
+    fn apply_removal(&mut self, event: &Event) -> Result<()> {
+        if !event.is_removal() {
+            return Ok(());
+        }
+        let mut removed = vec![];
+        self.data.retain(|_, ce| match &ce.event {
+            Event::Message(msg) if event.removes(msg) => {
+                removed.push(msg.clone());
+                false
+            }
+            _ => true,
+        });
+        self.edits
+            .retain(|_, ce| !removed.iter().any(|msg| refers_to(&ce.event, msg)));
+        let mut reacted = vec![];
+        self.reactions.retain(|_, ce| {
+            if removed.iter().any(|msg| refers_to(&ce.event, msg)) {
+                return false;
+            }
+            match &ce.event {
+                Event::Reaction(r) if event.removes_reaction(r) => {
+                    reacted.push((r.provider_name.clone(), r.target_msgid.clone()));
+                    false
+                }
+                _ => true,
+            }
+        });
+        for (provider_name, msgid) in reacted {
+            self.update_reactions(&provider_name, &msgid)?;
+        }
+        Ok(())
+    }
+    /// Counts the stored reactions to a message, once per user and key.
+    fn reactions_to(&self, provider_name: &str, msgid: &str) -> Vec<ReactionCount> {
+        let mut counts: Vec<ReactionCount> = vec![];
+        let mut seen = HashSet::new();
+        for ce in self.reactions.values() {
+            let r = match &ce.event {
+                Event::Reaction(r)
+                    if r.provider_name == provider_name && r.target_msgid == msgid =>
+                {
+                    r
+                }
+                _ => continue,
+            };
+            let user = if r.user_id.is_empty() {
+                &r.username
+            } else {
+                &r.user_id
+            };
+            if !seen.insert((&r.key, user)) {
+                continue;
+            }
+            let pos = match counts.iter().position(|c| c.key == r.key) {
+                Some(pos) => pos,
+                None => {
+                    counts.push(ReactionCount {
+                        key: r.key.clone(),
+                        ..Default::default()
+                    });
+                    counts.len() - 1
+                }
+            };
+            counts[pos].count += 1;
+            counts[pos].users.push(r.username.clone());
+        }
+        counts
+    }
+    /// Recounts the reactions of a stored message.
+    fn update_reactions(&mut self, provider_name: &str, msgid: &str) -> Result<()> {
+        let counts = self.reactions_to(provider_name, msgid);
+        for ce in self.data.values_mut() {
+            if let Event::Message(msg) = &mut ce.event {
+                if msg.provider_name == provider_name && msg.msgid == msgid {
+                    msg.reactions = counts.clone();
+                    ce.json = ce.event.to_json()?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds a reaction just stored to the count of its message.
+    fn apply_reaction(&mut self, key: &EventId) -> Result<()> {
+        let (provider_name, msgid) = match &self.reactions[key].event {
+            Event::Reaction(r) => (r.provider_name.clone(), r.target_msgid.clone()),
+            _ => return Ok(()),
+        };
+        self.update_reactions(&provider_name, &msgid)
+    }
+    /// Gives the stored reactions to a message seen again.
+    fn apply_stored_reactions(&self, event: &mut Event) {
+        if let Event::Message(msg) = event {
+            let counts = self.reactions_to(&msg.provider_name, &msg.msgid);
+            if !counts.is_empty() {
+                msg.reactions = counts;
+            }
+        }
+    }
+    /// Puts the text of an edit into the message it refers to.
+    fn apply_edit(&mut self, event: &Event) -> Result<()> {
+        let edit = match event {
+            Event::MessageEdited(edit) => edit,
+            _ => return Ok(()),
+        };
+        for ce in self.data.values_mut() {
+            if let Event::Message(msg) = &mut ce.event {
+                if edit.edits(msg) {
+                    set_text(msg, &edit.message);
+                    ce.json = ce.event.to_json()?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Completes the reference of a reply with what's known of its parent.
+    fn link_reply(&self, event: &mut Event) {
+        let msg = match event {
+            Event::Message(msg) => msg,
+            _ => return,
+        };
+        let reply = match &mut msg.reply_to {
+            Some(reply) if reply.timestamp.is_none() => reply,
+            _ => return,
+        };
+        let parent = self.data.values().rev().find_map(|ce| match &ce.event {
+            Event::Message(parent)
+                if parent.provider_name == msg.provider_name && parent.msgid == reply.msgid =>
+            {
+                Some(parent)
+            }
+            _ => None,
+        });
+        if let Some(parent) = parent {
+            reply.timestamp = Some(parent.timestamp);
+            if reply.author.id.is_empty() {
+                reply.author = parent.author.clone();
+            }
+            if reply.snippet.is_empty() {
+                reply.snippet = crate::ReplyTo::snippet_of(&parent.message);
+            }
+        }
+    }
+    /// Gives the text of the last stored edit to a message seen again.
+    fn apply_stored_edit(&self, event: &mut Event) {
+        if let Event::Message(msg) = event {
+            let edited = self.edits.values().rev().find_map(|ce| match &ce.event {
+                Event::MessageEdited(edit) if edit.edits(msg) => Some(edit.message.clone()),
+                _ => None,
+            });
+            if let Some(text) = edited {
+                set_text(msg, &text);
+            }
+        }
+    }
+    fn push_int(&mut self, mut event: Event) -> Result<()> {
+        if self.is_removed(&event) {
+            return Ok(());
+        }
+        let key = EventId::from_event(&event);
+        if event.is_removal() {
+            self.apply_removal(&event)?;
+            self.removals.insert(key, CachedEvent::from_event(event)?);
+            self.prune();
+            return Ok(());
+        }
+        if let Event::Reaction(_) = event {
+            self.reactions
+                .insert(key.clone(), CachedEvent::from_event(event)?);
+            self.prune();
+            return self.apply_reaction(&key);
+        }
+        if let Event::MessageEdited(_) = event {
+            self.apply_edit(&event)?;
+            self.edits.insert(key, CachedEvent::from_event(event)?);
+            self.prune();
+            return Ok(());
+        }
+        self.apply_stored_edit(&mut event);
+        self.apply_stored_reactions(&mut event);
+        self.link_reply(&mut event);
+        event.tokenize();
         while self.data.len() >= self.maxsize {
-            let first = self.data.iter().next().unwrap().0.clone();
-            self.data.remove(&first);
+            self.evict_oldest();
         }
 
-        let key = EventId::from_event(&event);
         let cm = CachedEvent::from_event(event)?;
         self.data.insert(key, cm);
+        self.prune();
 
         Ok(())
     }
-    pub async fn push(&mut self, event: Event) -> Result<MessageIgnored> {
+    /// Stores an event, unless it's ignored. Returns the event as stored, with
+    /// its text split, its reply linked and the known edits and reactions
+    /// applied, so everyone downstream gets the same; or as it came if ignored.
+    pub async fn push(&mut self, mut event: Event) -> Result<(MessageIgnored, Arc<Event>)> {
         let key = EventId::from_event(&event);
-        if self.data.get(&key).is_some() {
-            return Ok(MessageIgnored::Duplicated);
+        if self.data.contains_key(&key)
+            || self.removals.contains_key(&key)
+            || self.reactions.contains_key(&key)
+            || self.edits.contains_key(&key)
+        {
+            return Ok((MessageIgnored::Duplicated, Arc::new(event)));
+        }
+        if self.is_removed(&event) {
+            return Ok((MessageIgnored::Deleted, Arc::new(event)));
+        }
+        if self.is_too_old(&event) {
+            return Ok((MessageIgnored::TooOld, Arc::new(event)));
+        }
+        if event.is_removal() {
+            self.apply_removal(&event)?;
+            let ce = CachedEvent::from_event(event)?;
+            let (json, stored) = (ce.json.clone(), Arc::new(ce.event.clone()));
+            self.removals.insert(key, ce);
+            self.prune();
+            self.log(json).await?;
+            return Ok((MessageIgnored::None, stored));
+        }
+        if let Event::Reaction(_) = event {
+            let ce = CachedEvent::from_event(event)?;
+            let (json, stored) = (ce.json.clone(), Arc::new(ce.event.clone()));
+            self.reactions.insert(key.clone(), ce);
+            self.prune();
+            self.apply_reaction(&key)?;
+            self.log(json).await?;
+            return Ok((MessageIgnored::None, stored));
+        }
+        if let Event::MessageEdited(_) = event {
+            self.apply_edit(&event)?;
+            let ce = CachedEvent::from_event(event)?;
+            let (json, stored) = (ce.json.clone(), Arc::new(ce.event.clone()));
+            self.edits.insert(key, ce);
+            self.prune();
+            self.log(json).await?;
+            return Ok((MessageIgnored::None, stored));
         }
         while self.data.len() >= self.maxsize {
-            let first = self.data.iter().next().unwrap().0.clone();
-            if first.timestamp > event.timestamp() {
-                return Ok(MessageIgnored::TooOld);
-            }
-            self.data.remove(&first);
+            self.evict_oldest();
         }
 
+        self.apply_stored_edit(&mut event);
+        self.apply_stored_reactions(&mut event);
+        self.link_reply(&mut event);
+        event.tokenize();
         let ce = CachedEvent::from_event(event)?;
-        let json = ce.json.clone();
+        let (json, stored) = (ce.json.clone(), Arc::new(ce.event.clone()));
         self.data.insert(key, ce);
+        self.prune();
         self.log(json).await?;
 
-        Ok(MessageIgnored::None)
+        Ok((MessageIgnored::None, stored))
+    }
+}
+
+/// Whether the event is a reaction to the message or an edit of it.
+fn refers_to(event: &Event, msg: &crate::Message) -> bool {
+    match event {
+        Event::Reaction(r) => r.provider_name == msg.provider_name && r.target_msgid == msg.msgid,
+        Event::MessageEdited(edit) => edit.edits(msg),
+        _ => false,
     }
 }
 
+/// Replaces the text of an edited message. The positions of the emotes and
+/// cheermotes are of the old text, so they're dropped.
+fn set_text(msg: &mut crate::Message, text: &str) {
+    msg.message = text.to_owned();
+    msg.emotes.clear();
+    msg.cheermotes.clear();
+    msg.tokenize();
+}
+
 fn human_duration(d: Duration) -> String {
     let mut t = d.as_secs();
     if t < 120 {
@@ -0,0 +1,144 @@
+//! Messages split into the pieces to render: text, emotes, mentions, links
+//! and cheermotes. Built once when the message is stored, so every consumer
+//! shows the same thing without working out the emote positions by itself.
+use crate::{Cheermote, Emote};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fragment {
+    Text {
+        text: String,
+    },
+    Emote {
+        /// The text replaced by the emote, i.e. its name.
+        text: String,
+        id: String,
+        url: String,
+    },
+    /// "@username", `username` has no "@" nor trailing punctuation.
+    Mention {
+        text: String,
+        username: String,
+    },
+    Link {
+        text: String,
+        url: String,
+    },
+    Cheermote {
+        text: String,
+        bits: u32,
+        url: String,
+        color: String,
+    },
+}
+
+impl Fragment {
+    /// The original text of the message for this fragment.
+    pub fn text(&self) -> &str {
+        match self {
+            Fragment::Text { text }
+            | Fragment::Emote { text, .. }
+            | Fragment::Mention { text, .. }
+            | Fragment::Link { text, .. }
+            | Fragment::Cheermote { text, .. } => text,
+        }
+    }
+    /// Emotes and cheermotes, that are shown as images.
+    pub fn is_image(&self) -> bool {
+        matches!(self, Fragment::Emote { .. } | Fragment::Cheermote { .. })
+    }
+}
+
+/// Splits a message using the positions of its emotes and cheermotes, in
+/// characters, and finds mentions and links in the rest of the text.
+/// Positions that overlap or fall outside the text are ignored.
+pub fn tokenize(text: &str, emotes: &[Emote], cheermotes: &[Cheermote]) -> Vec<Fragment> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut ranges: Vec<(usize, usize, Fragment)> = vec![];
+    for emote in emotes {
+        let fragment = Fragment::Emote {
+            text: String::new(),
+            id: emote.id.clone(),
+            url: emote.url.clone(),
+        };
+        ranges.push((emote.from, emote.to, fragment));
+    }
+    for cheermote in cheermotes {
+        let fragment = Fragment::Cheermote {
+            text: String::new(),
+            bits: cheermote.bits,
+            url: cheermote.url.clone(),
+            color: cheermote.color.clone(),
+        };
+        ranges.push((cheermote.from, cheermote.to, fragment));
+    }
+    ranges.sort_by_key(|(from, _, _)| *from);
+
+    let mut fragments = vec![];
+    let mut pos = 0;
+    for (from, to, mut fragment) in ranges {
+        if from < pos || to < from || to >= chars.len() {
+            continue;
+        }
+        push_words(&mut fragments, &chars[pos..from].iter().collect::<String>());
+        let range_text: String = chars[from..=to].iter().collect();
+        match &mut fragment {
+            Fragment::Emote { text, .. } | Fragment::Cheermote { text, .. } => *text = range_text,
+            _ => unreachable!("only emotes and cheermotes have positions"),
+        }
+        fragments.push(fragment);
+        pos = to + 1;
+    }
+    push_words(&mut fragments, &chars[pos..].iter().collect::<String>());
+    fragments
+}
+
+/// Adds plain text, with its mentions and links as their own fragments.
+fn push_words(fragments: &mut Vec<Fragment>, text: &str) {
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end_matches(char::is_whitespace);
+        // "@bob," mentions "bob", and "(see https://x.org)." links "https://x.org".
+        let trimmed = word.trim_end_matches(|c: char| ".,;:!?)'\"".contains(c));
+        if let Some(username) = trimmed.strip_prefix('@').filter(|u| is_username(u)) {
+            fragments.push(Fragment::Mention {
+                text: trimmed.to_owned(),
+                username: username.to_owned(),
+            });
+        } else if is_link(trimmed) {
+            fragments.push(Fragment::Link {
+                text: trimmed.to_owned(),
+                url: trimmed.to_owned(),
+            });
+        } else {
+            push_text(fragments, piece);
+            continue;
+        }
+        push_text(fragments, &piece[trimmed.len()..]);
+    }
+}
+
+fn push_text(fragments: &mut Vec<Fragment>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match fragments.last_mut() {
+        Some(Fragment::Text { text: last }) => last.push_str(text),
+        _ => fragments.push(Fragment::Text {
+            text: text.to_owned(),
+        }),
+    }
+}
+
+fn is_username(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-.:".contains(c))
+}
+
+fn is_link(word: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| word.len() > scheme.len() && word.starts_with(scheme))
+}
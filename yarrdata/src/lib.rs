@@ -1,31 +1,212 @@
-extern crate bus_queue;
 pub mod db;
-use anyhow::Result;
-use bus_queue::{bounded, Publisher, Subscriber};
-use futures::executor::block_on;
-use futures::{FutureExt, SinkExt, StreamExt};
+pub mod fragment;
+pub mod provider;
+pub mod router;
+pub mod schema;
+pub mod status;
+use anyhow::{anyhow, Result};
+use futures::Stream;
 use serde::{Deserialize, Serialize};
-use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+pub use fragment::Fragment;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     Message(Message),
+    MessageEdited(MessageEdited),
+    MessageDeleted(MessageDeleted),
+    UserPurged(UserPurged),
+    ChatCleared(ChatCleared),
+    Subscription(Subscription),
+    SubGift(SubGift),
+    Raid(Raid),
+    Announcement(Announcement),
+    Reaction(Reaction),
 }
 
 impl Event {
+    /// Reads a line of the JSONL files, in any version of the schema.
     pub fn from_json(text: &str) -> Result<Self> {
-        let m: Self = serde_json::from_str(text.trim())?;
+        let (m, _) = schema::from_json(text)?;
         Ok(m)
     }
+    /// A line for the JSONL files, in the current version of the schema.
     pub fn to_json(&self) -> Result<String> {
-        let mut s = serde_json::to_string(self)?;
+        let mut s = schema::to_json(self)?;
         s.push('\n');
         Ok(s)
     }
+    /// When it happened, in milliseconds since the UNIX epoch.
     pub fn timestamp(&self) -> u64 {
         match self {
             Event::Message(m) => m.timestamp,
+            Event::MessageEdited(e) => e.timestamp,
+            Event::MessageDeleted(d) => d.timestamp,
+            Event::UserPurged(p) => p.timestamp,
+            Event::ChatCleared(c) => c.timestamp,
+            Event::Subscription(n) => n.timestamp,
+            Event::SubGift(n) => n.timestamp,
+            Event::Raid(n) => n.timestamp,
+            Event::Announcement(n) => n.timestamp,
+            Event::Reaction(r) => r.timestamp,
+        }
+    }
+    pub fn timestamp_mut(&mut self) -> &mut u64 {
+        match self {
+            Event::Message(m) => &mut m.timestamp,
+            Event::MessageEdited(e) => &mut e.timestamp,
+            Event::MessageDeleted(d) => &mut d.timestamp,
+            Event::UserPurged(p) => &mut p.timestamp,
+            Event::ChatCleared(c) => &mut c.timestamp,
+            Event::Subscription(n) => &mut n.timestamp,
+            Event::SubGift(n) => &mut n.timestamp,
+            Event::Raid(n) => &mut n.timestamp,
+            Event::Announcement(n) => &mut n.timestamp,
+            Event::Reaction(r) => &mut r.timestamp,
+        }
+    }
+    pub fn provider_name(&self) -> &str {
+        match self {
+            Event::Message(m) => &m.provider_name,
+            Event::MessageEdited(e) => &e.provider_name,
+            Event::MessageDeleted(d) => &d.provider_name,
+            Event::UserPurged(p) => &p.provider_name,
+            Event::ChatCleared(c) => &c.provider_name,
+            Event::Subscription(n) => &n.provider_name,
+            Event::SubGift(n) => &n.provider_name,
+            Event::Raid(n) => &n.provider_name,
+            Event::Announcement(n) => &n.provider_name,
+            Event::Reaction(r) => &r.provider_name,
+        }
+    }
+    pub fn room(&self) -> &str {
+        match self {
+            Event::Message(m) => &m.room,
+            Event::MessageEdited(e) => &e.room,
+            Event::MessageDeleted(d) => &d.room,
+            Event::UserPurged(p) => &p.room,
+            Event::ChatCleared(c) => &c.room,
+            Event::Subscription(n) => &n.room,
+            Event::SubGift(n) => &n.room,
+            Event::Raid(n) => &n.room,
+            Event::Announcement(n) => &n.room,
+            Event::Reaction(r) => &r.room,
+        }
+    }
+    pub fn username(&self) -> &str {
+        match self {
+            Event::Message(m) => &m.username,
+            Event::MessageEdited(e) => &e.username,
+            Event::MessageDeleted(d) => &d.username,
+            Event::UserPurged(p) => &p.username,
+            Event::ChatCleared(_) => "",
+            Event::Subscription(n) => &n.username,
+            Event::SubGift(n) => &n.username,
+            Event::Raid(n) => &n.username,
+            Event::Announcement(n) => &n.username,
+            Event::Reaction(r) => &r.username,
+        }
+    }
+    pub fn msgid(&self) -> &str {
+        match self {
+            Event::Message(m) => &m.msgid,
+            Event::MessageEdited(e) => &e.msgid,
+            Event::MessageDeleted(d) => &d.msgid,
+            Event::UserPurged(p) => &p.msgid,
+            Event::ChatCleared(c) => &c.msgid,
+            Event::Subscription(n) => &n.msgid,
+            Event::SubGift(n) => &n.msgid,
+            Event::Raid(n) => &n.msgid,
+            Event::Announcement(n) => &n.msgid,
+            Event::Reaction(r) => &r.msgid,
+        }
+    }
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Message(_) => EventKind::Message,
+            Event::MessageEdited(_) => EventKind::MessageEdited,
+            Event::MessageDeleted(_) => EventKind::MessageDeleted,
+            Event::UserPurged(_) => EventKind::UserPurged,
+            Event::ChatCleared(_) => EventKind::ChatCleared,
+            Event::Subscription(_) => EventKind::Subscription,
+            Event::SubGift(_) => EventKind::SubGift,
+            Event::Raid(_) => EventKind::Raid,
+            Event::Announcement(_) => EventKind::Announcement,
+            Event::Reaction(_) => EventKind::Reaction,
+        }
+    }
+    /// What the user wrote, for the events that have text.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Event::Message(m) => Some(&m.message),
+            Event::MessageEdited(e) => Some(&e.message),
+            Event::Subscription(n) => Some(&n.message),
+            Event::Announcement(n) => Some(&n.message),
+            _ => None,
+        }
+    }
+    /// Fills the fragments of the events that have text, if they don't have them yet.
+    pub fn tokenize(&mut self) {
+        let (fragments, text, emotes, cheermotes) = match self {
+            Event::Message(m) => (
+                &mut m.fragments,
+                &m.message,
+                &m.emotes[..],
+                &m.cheermotes[..],
+            ),
+            Event::MessageEdited(e) => (&mut e.fragments, &e.message, &[][..], &[][..]),
+            Event::Subscription(n) => (&mut n.fragments, &n.message, &n.emotes[..], &[][..]),
+            Event::Announcement(n) => (&mut n.fragments, &n.message, &n.emotes[..], &[][..]),
+            _ => return,
+        };
+        if fragments.is_empty() {
+            *fragments = fragment::tokenize(text, emotes, cheermotes);
+        }
+    }
+    /// Who sent it, for events that carry one.
+    pub fn author(&self) -> Option<&Author> {
+        match self {
+            Event::Message(m) => Some(&m.author),
+            _ => None,
+        }
+    }
+    /// Whether this event takes messages off the chat (deletions, purges and clears).
+    pub fn is_removal(&self) -> bool {
+        matches!(
+            self,
+            Event::MessageDeleted(_) | Event::UserPurged(_) | Event::ChatCleared(_)
+        )
+    }
+    /// Whether this is the deletion of the given reaction.
+    pub fn removes_reaction(&self, reaction: &Reaction) -> bool {
+        matches!(self, Event::MessageDeleted(d)
+            if d.provider_name == reaction.provider_name && d.target_msgid == reaction.msgid)
+    }
+    /// Whether this is a removal that applies to the given message.
+    pub fn removes(&self, msg: &Message) -> bool {
+        match self {
+            Event::MessageDeleted(d) => {
+                d.provider_name == msg.provider_name && d.target_msgid == msg.msgid
+            }
+            Event::UserPurged(p) => {
+                p.provider_name == msg.provider_name
+                    && p.room == msg.room
+                    // Twitch sends the login, while messages carry the display name.
+                    && (p.username == msg.author.login
+                        || p.username.eq_ignore_ascii_case(&msg.username))
+                    && msg.timestamp <= p.timestamp
+            }
+            Event::ChatCleared(c) => {
+                c.provider_name == msg.provider_name
+                    && c.room == msg.room
+                    && msg.timestamp <= c.timestamp
+            }
+            _ => false,
         }
     }
 }
@@ -41,6 +222,14 @@ pub fn default_timestamp() -> u64 {
     since_the_epoch.as_secs()
 }
 
+/// Current time in milliseconds, the unit of the timestamps of the events.
+pub fn default_timestamp_ms() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let start = SystemTime::now();
+    let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
+    since_the_epoch.as_millis() as u64
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Message {
     // To add robustness when deserializing, we must have defaults for everything.
@@ -50,16 +239,363 @@ pub struct Message {
     pub room: String,
     #[serde(default)]
     pub message: String,
+    /// Name to show, same as `author.display_name` when there's an author.
+    #[serde(default)]
+    pub username: String,
+    /// Who sent it. Messages stored by older versions only have `username`.
+    #[serde(default)]
+    pub author: Author,
+    #[serde(default)]
+    pub msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub badges: Vec<Badge>,
+    #[serde(default)]
+    pub emotes: Vec<Emote>,
+    /// Bits cheered with the message, in total.
+    #[serde(default)]
+    pub bits: u32,
+    #[serde(default)]
+    pub cheermotes: Vec<Cheermote>,
+    /// `message` split into what to show, see `fragment::tokenize`.
+    #[serde(default)]
+    pub fragments: Vec<Fragment>,
+    /// The message this one answers to, if it's a reply.
+    #[serde(default)]
+    pub reply_to: Option<Box<ReplyTo>>,
+    /// Reactions to the message, counted by the database from `Reaction` events.
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+}
+
+impl Message {
+    /// Computes `fragments` from the text, emotes and cheermotes.
+    pub fn tokenize(&mut self) {
+        self.fragments = fragment::tokenize(&self.message, &self.emotes, &self.cheermotes);
+    }
+}
+
+/// The variants of `Event`, e.g. to filter on them in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Message,
+    MessageEdited,
+    MessageDeleted,
+    UserPurged,
+    ChatCleared,
+    Subscription,
+    SubGift,
+    Raid,
+    Announcement,
+    Reaction,
+}
+
+/// The user that sent a message, as given by the provider.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct Author {
+    /// Stable id for the user in the provider: the Twitch user id or the full Matrix ID.
+    #[serde(default)]
+    pub id: String,
+    /// Account name, e.g. "someuser" on Twitch or the localpart on Matrix.
+    #[serde(default)]
+    pub login: String,
+    #[serde(default)]
+    pub display_name: String,
+    /// Color chosen by the user for the name, like "#1E90FF". Empty if none.
+    #[serde(default)]
+    pub color: String,
+    #[serde(default)]
+    pub avatar_url: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+}
+
+impl Author {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+}
+
+/// Reference from a reply to the message it answers to.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct ReplyTo {
+    /// `msgid` of the parent message, which is always from the same provider.
+    #[serde(default)]
+    pub msgid: String,
+    /// Timestamp of the parent, filled in when the parent is in the database.
+    /// Together with the provider and `msgid` it makes its `db::EventId`.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Who wrote the parent, as far as the provider tells.
+    #[serde(default)]
+    pub author: Author,
+    /// The beginning of the parent's text, see `ReplyTo::snippet_of`.
+    #[serde(default)]
+    pub snippet: String,
+    /// `msgid` of the first message of the thread, when the parent is itself a reply.
+    #[serde(default)]
+    pub thread_msgid: String,
+}
+
+impl ReplyTo {
+    /// Longest snippet kept, in characters.
+    pub const SNIPPET_LEN: usize = 80;
+
+    /// Cuts a text to `SNIPPET_LEN` characters, adding "…" if it was longer.
+    pub fn snippet_of(text: &str) -> String {
+        let text = text.trim();
+        match text.char_indices().nth(Self::SNIPPET_LEN) {
+            Some((pos, _)) => format!("{}…", text[..pos].trim_end()),
+            None => text.to_owned(),
+        }
+    }
+}
+
+/// Roles of a user in the room, normalized across providers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Broadcaster,
+    Moderator,
+    Vip,
+    Subscriber,
+}
+
+/// Subscription plans on Twitch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SubTier {
+    Prime,
+    #[default]
+    Tier1,
+    Tier2,
+    Tier3,
+}
+
+/// A user subscribed, or renewed the subscription.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Subscription {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
     #[serde(default)]
     pub username: String,
     #[serde(default)]
     pub msgid: String,
-    #[serde(default = "default_timestamp")]
+    #[serde(default = "default_timestamp_ms")]
     pub timestamp: u64,
+    /// Total months subscribed, including this one.
+    #[serde(default)]
+    pub months: u32,
+    #[serde(default)]
+    pub tier: SubTier,
+    /// The text the provider shows for it, e.g. "bob subscribed at Tier 1."
+    #[serde(default)]
+    pub system_message: String,
+    /// What the user wrote along with it, if anything.
+    #[serde(default)]
+    pub message: String,
     #[serde(default)]
     pub badges: Vec<Badge>,
     #[serde(default)]
     pub emotes: Vec<Emote>,
+    #[serde(default)]
+    pub fragments: Vec<Fragment>,
+}
+
+/// Subscriptions given away by `username`, to `recipient` or to `count`
+/// random users of the chat.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SubGift {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    /// The gifter.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+    /// Empty when the subscriptions go to random users.
+    #[serde(default)]
+    pub recipient: String,
+    #[serde(default)]
+    pub count: u32,
+    #[serde(default)]
+    pub tier: SubTier,
+    #[serde(default)]
+    pub system_message: String,
+}
+
+/// Another channel, `username`, raided this one.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Raid {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    /// The raider.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub viewers: u32,
+    #[serde(default)]
+    pub system_message: String,
+}
+
+/// A highlighted message sent by the broadcaster or a moderator.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Announcement {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub message: String,
+    /// Highlight color chosen, e.g. "PRIMARY" or "BLUE".
+    #[serde(default)]
+    pub color: String,
+    #[serde(default)]
+    pub badges: Vec<Badge>,
+    #[serde(default)]
+    pub emotes: Vec<Emote>,
+    #[serde(default)]
+    pub fragments: Vec<Fragment>,
+}
+
+/// New text for a message already sent.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct MessageEdited {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub username: String,
+    /// Id of the edit itself.
+    #[serde(default)]
+    pub msgid: String,
+    /// `msgid` of the message edited.
+    #[serde(default)]
+    pub target_msgid: String,
+    /// The whole new text.
+    #[serde(default)]
+    pub message: String,
+    /// The new text split for rendering; edits don't carry emotes.
+    #[serde(default)]
+    pub fragments: Vec<Fragment>,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+}
+
+impl MessageEdited {
+    pub fn edits(&self, msg: &Message) -> bool {
+        self.provider_name == msg.provider_name && self.target_msgid == msg.msgid
+    }
+}
+
+/// A single message removed, by a moderator or by its author.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct MessageDeleted {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    /// Author of the removed message, if the provider tells.
+    #[serde(default)]
+    pub username: String,
+    /// Id of this event. Providers that don't have one make it up from the target.
+    #[serde(default)]
+    pub msgid: String,
+    /// `msgid` of the message removed.
+    #[serde(default)]
+    pub target_msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+}
+
+/// Someone reacted to a message, usually with an emoji (Matrix `m.reaction`).
+/// Taking it back deletes it like a message, with `MessageDeleted`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Reaction {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    /// Who reacted.
+    #[serde(default)]
+    pub username: String,
+    /// Stable id of who reacted, like `Author::id`.
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub msgid: String,
+    /// `msgid` of the message reacted to.
+    #[serde(default)]
+    pub target_msgid: String,
+    /// The emoji, or whatever text was used to react.
+    #[serde(default)]
+    pub key: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+}
+
+/// How many users reacted to a message with the same key.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct ReactionCount {
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub count: u32,
+    /// Names of the users, in the order they reacted.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Every message of a user up to now removed, i.e. the user was banned or timed out.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct UserPurged {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub msgid: String,
+    /// Length of the timeout in seconds, or `None` for a permanent ban.
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
+}
+
+/// Every message in a room up to now removed.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ChatCleared {
+    #[serde(default = "default_provider")]
+    pub provider_name: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub msgid: String,
+    #[serde(default = "default_timestamp_ms")]
+    pub timestamp: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -88,66 +624,224 @@ pub struct Emote {
     pub url: String,
 }
 
+/// A cheer inside a message, like "Cheer100". Positions work like in `Emote`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Cheermote {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub bits: u32,
+    #[serde(default)]
+    pub from: usize,
+    #[serde(default)]
+    pub to: usize,
+    /// The text replaced by the image, prefix and amount.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub url: String,
+    /// Color of the tier for the amount, e.g. "#9c3ee8".
+    #[serde(default)]
+    pub color: String,
+}
+
+/// Carries the events of a provider client to the daemon.
+///
+/// Nothing is lost on the way: the queue holds up to `SIZE` events, and once
+/// full `publish` waits for the subscriber to take one, slowing the client
+/// down instead of dropping what it received. There's a single subscriber,
+/// the one that stores the events; the consumers after the database get them
+/// from a `router::Router`, where falling behind is recoverable.
 pub struct ProviderQueue {
     pub provider_name: String,
-    pub publisher: Publisher<Event>,
-    pub subscriber: Subscriber<Event>,
+    /// `None` once closed, which ends the subscription.
+    sender: Option<mpsc::Sender<Arc<Event>>>,
+    /// Until taken by `subscribe`.
+    receiver: Mutex<Option<mpsc::Receiver<Arc<Event>>>>,
+    pub stats: QueueStats,
+}
+
+/// Counts the events going through a queue, to know how many are waiting.
+/// The publishing side is counted by `ProviderQueue`, the consumer has to
+/// call `consumed` for each event it takes.
+#[derive(Clone, Debug, Default)]
+pub struct QueueStats {
+    published: Arc<AtomicU64>,
+    consumed: Arc<AtomicU64>,
+}
+
+impl QueueStats {
+    pub fn consumed(&self) {
+        self.consumed.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Events published and not consumed yet.
+    pub fn depth(&self) -> u64 {
+        let consumed = self.consumed.load(Ordering::Relaxed);
+        let published = self.published.load(Ordering::Relaxed);
+        published.saturating_sub(consumed)
+    }
+    /// Forgets about the pending events, e.g. when the queue was dropped.
+    pub fn clear(&self) {
+        let published = self.published.load(Ordering::Relaxed);
+        self.consumed.store(published, Ordering::Relaxed);
+    }
 }
 
 impl ProviderQueue {
-    const SIZE: usize = 1024;
+    pub const SIZE: usize = 1024;
     pub fn new(provider_name: String) -> Self {
-        let (pb, sb) = bounded::<Event>(Self::SIZE);
+        Self::with_capacity(provider_name, Self::SIZE)
+    }
+    /// A queue that holds up to `size` events before `publish` has to wait.
+    pub fn with_capacity(provider_name: String, size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(size);
         Self {
             provider_name,
-            publisher: pb,
-            subscriber: sb,
+            sender: Some(sender),
+            receiver: Mutex::new(Some(receiver)),
+            stats: QueueStats::default(),
         }
     }
-    pub fn close_sync(&mut self) -> Result<()> {
-        Ok(block_on(async move { self.publisher.close().await })?)
-    }
-    pub fn publish_sync(&mut self, e: Event) -> Result<()> {
-        Ok(block_on(async move { self.publisher.send(e).await })?)
+    /// Ends the subscription once it received every event already published.
+    pub fn close(&mut self) {
+        self.sender = None;
     }
-    pub fn subscribe(&self) -> Subscriber<Event> {
-        self.subscriber.clone()
+    /// Queues an event for the subscriber, waiting if the queue is full.
+    pub async fn publish(&self, e: Event) -> Result<()> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| anyhow!("queue of {} is closed", self.provider_name))?;
+        // Only fails if the subscriber is gone, and then nobody will miss it.
+        if sender.send(Arc::new(e)).await.is_ok() {
+            self.stats.published.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
     }
-    pub fn subscribe_sync(&self) -> SyncSubscriber {
-        SyncSubscriber {
-            subscriber: self.subscriber.clone(),
+    /// Receives the events, including the ones published before subscribing.
+    /// Only the first subscription gets them, later ones end right away.
+    pub fn subscribe(&self) -> QueueSubscriber {
+        let rx = match self.receiver.lock().unwrap().take() {
+            Some(rx) => rx,
+            None => mpsc::channel(1).1,
+        };
+        QueueSubscriber {
+            rx: SubscriberRx::Queue(rx),
         }
     }
 }
 
-pub enum Status {
+/// What a `QueueSubscriber` got from its queue.
+#[derive(Debug)]
+pub enum Received {
     Event(Arc<Event>),
+    /// The subscriber was too slow and this many events were skipped. Only
+    /// happens to the subscriptions of a `router::Router`.
+    Lagged(u64),
+    /// The queue was closed and every event was received.
     Closed,
-    Error,
 }
 
-impl Status {
-    pub fn new(v: Option<Option<Arc<Event>>>) -> Self {
-        match v {
-            None => Self::Closed,
-            Some(x) => match x {
-                None => Self::Error,
-                Some(y) => Self::Event(y),
+enum SubscriberRx {
+    Queue(mpsc::Receiver<Arc<Event>>),
+    /// With the count of skipped events shared by the router.
+    Route(broadcast::Receiver<Arc<Event>>, Arc<AtomicU64>),
+}
+
+pub struct QueueSubscriber {
+    rx: SubscriberRx,
+}
+
+impl QueueSubscriber {
+    pub(crate) fn route(rx: broadcast::Receiver<Arc<Event>>, missed: Arc<AtomicU64>) -> Self {
+        Self {
+            rx: SubscriberRx::Route(rx, missed),
+        }
+    }
+    /// Waits for the next event.
+    pub async fn recv(&mut self) -> Received {
+        match &mut self.rx {
+            SubscriberRx::Queue(rx) => match rx.recv().await {
+                Some(event) => Received::Event(event),
+                None => Received::Closed,
+            },
+            SubscriberRx::Route(rx, missed) => match rx.recv().await {
+                Ok(event) => Received::Event(event),
+                Err(RecvError::Lagged(count)) => {
+                    missed.fetch_add(count, Ordering::Relaxed);
+                    Received::Lagged(count)
+                }
+                Err(RecvError::Closed) => Received::Closed,
+            },
+        }
+    }
+    /// Like `recv`, but returns `None` instead of waiting if nothing is ready.
+    pub fn try_recv(&mut self) -> Option<Received> {
+        match &mut self.rx {
+            SubscriberRx::Queue(rx) => match rx.try_recv() {
+                Ok(event) => Some(Received::Event(event)),
+                Err(mpsc::error::TryRecvError::Empty) => None,
+                Err(mpsc::error::TryRecvError::Disconnected) => Some(Received::Closed),
+            },
+            SubscriberRx::Route(rx, missed) => match rx.try_recv() {
+                Ok(event) => Some(Received::Event(event)),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Lagged(count)) => {
+                    missed.fetch_add(count, Ordering::Relaxed);
+                    Some(Received::Lagged(count))
+                }
+                Err(TryRecvError::Closed) => Some(Received::Closed),
             },
         }
     }
+    /// Turns the subscription into a stream, which ends when the queue is closed.
+    pub fn into_stream(self) -> impl Stream<Item = Received> {
+        futures::stream::unfold(self, |mut sub| async move {
+            match sub.recv().await {
+                Received::Closed => None,
+                received => Some((received, sub)),
+            }
+        })
+    }
+}
+
+/// Asks a running service to stop. All clones refer to the same service.
+#[derive(Clone, Debug)]
+pub struct StopHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        // Can only fail if the service is gone, and then it's already stopped.
+        let _ = self.tx.send(true);
+    }
 }
 
-pub struct SyncSubscriber {
-    pub subscriber: Subscriber<Event>,
+/// Held by a service to know when it has been asked to stop.
+#[derive(Clone, Debug)]
+pub struct StopSignal {
+    rx: watch::Receiver<bool>,
 }
-impl SyncSubscriber {
-    pub fn get_next(&mut self) -> Status {
-        Status::new(self.subscriber.next().now_or_never())
+
+impl StopSignal {
+    pub fn is_stopped(&self) -> bool {
+        *self.rx.borrow()
     }
-    pub fn peek_next(&mut self) -> Status {
-        let mut p = self.subscriber.clone().peekable();
-        let peek = Pin::new(&mut p).peek();
-        Status::new(peek.now_or_never().map(|x| x.cloned()))
+    /// Resolves once a stop has been requested.
+    pub async fn stopped(&mut self) {
+        while !*self.rx.borrow() {
+            if self.rx.changed().await.is_err() {
+                // No handles left, so nobody can ask us to stop anymore.
+                futures::future::pending::<()>().await;
+            }
+        }
     }
 }
+
+pub fn stop_channel() -> (StopHandle, StopSignal) {
+    let (tx, rx) = watch::channel(false);
+    (StopHandle { tx: Arc::new(tx) }, StopSignal { rx })
+}
+
+mod tests;
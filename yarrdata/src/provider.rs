@@ -0,0 +1,29 @@
+//! What the daemon needs from the client of a chat network. Each network
+//! implements `Provider` and has its own section type in the config, and the
+//! daemon runs one client per section.
+use crate::status::StatusHandle;
+use crate::{QueueStats, QueueSubscriber, StopHandle};
+use anyhow::Result;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Provider: Send + 'static {
+    /// Its section in the config, e.g. `yarrcfg::Twitch` for `[twitch.NAME]`.
+    type Config: Clone + PartialEq + Send + Sync + 'static;
+
+    /// Creates a client that isn't connected yet.
+    async fn new(cfg: &Self::Config) -> Result<Self>
+    where
+        Self: Sized;
+    /// Receives the events published by `run` from now on.
+    fn subscribe(&self) -> QueueSubscriber;
+    /// Returns a handle that makes `run` disconnect and return.
+    fn stop_handle(&self) -> StopHandle;
+    /// Reports the status through an existing handle, e.g. one kept across restarts.
+    fn set_status_handle(&mut self, status: StatusHandle);
+    /// Counts the queued events in existing stats, e.g. ones kept across restarts.
+    fn set_queue_stats(&mut self, stats: QueueStats);
+    /// Receives and publishes events until stopped or failed. The queue has
+    /// to be closed before returning, so the subscribers know it's over.
+    async fn run(&mut self) -> Result<()>;
+}
@@ -0,0 +1,147 @@
+//! Hands the stored events to the consumers that want them. Sinks, websocket
+//! clients and renderers describe what they want with a `Filter`, usually one
+//! of the `[filters.*]` of the config, so "only chat" or "only moderators"
+//! means the same for all of them.
+use crate::{Event, EventKind, QueueSubscriber, Role};
+use log::debug;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Which events a consumer wants. Each field that is set has to match, and a
+/// list matches when any of its entries does. The default matches everything.
+///
+/// Edits and removals only have to match `providers` and `rooms`: they change
+/// messages the consumer may be showing, whatever else it asked for.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Provider names like "twitch", or instances like "twitch.servername".
+    /// Instances are only known for new events, not for those already stored.
+    pub providers: Vec<String>,
+    pub rooms: Vec<String>,
+    pub kinds: Vec<EventKind>,
+    /// The author must have one of these. Events without an author don't match.
+    pub roles: Vec<Role>,
+    /// Has to be found in the text. Events without text don't match.
+    pub text: Option<TextPattern>,
+    /// Events matching this are left out, even if they match the rest.
+    pub exclude: Option<Box<Filter>>,
+}
+
+impl Filter {
+    /// `source` is the provider instance the event comes from, if known.
+    pub fn matches(&self, source: Option<&str>, event: &Event) -> bool {
+        let provider = |p: &String| p == event.provider_name() || Some(p.as_str()) == source;
+        let role = |r: &Role| event.author().map(|a| a.has_role(*r)) == Some(true);
+        let text = || match (&self.text, event.text()) {
+            (None, _) => true,
+            (Some(pattern), Some(text)) => pattern.0.is_match(text),
+            (Some(_), None) => false,
+        };
+        let excluded = || match &self.exclude {
+            Some(exclude) => exclude.matches(source, event),
+            None => false,
+        };
+        let place = (self.providers.is_empty() || self.providers.iter().any(provider))
+            && (self.rooms.is_empty() || self.rooms.iter().any(|r| r == event.room()));
+        if event.is_removal() || event.kind() == EventKind::MessageEdited {
+            return place;
+        }
+        place
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind()))
+            && (self.roles.is_empty() || self.roles.iter().any(role))
+            && text()
+            && !excluded()
+    }
+}
+
+/// A regular expression, written as a string in the config. Prefix it with
+/// `(?i)` to ignore case.
+#[derive(Debug, Clone)]
+pub struct TextPattern(pub Regex);
+
+impl PartialEq for TextPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for TextPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TextPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(TextPattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+struct Route {
+    name: String,
+    filter: Filter,
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+/// Fans the events out to named subscriptions, each with its own filter.
+/// Every provider publishes into the same router, so a subscription gets the
+/// events of all of them as a single stream. Cheap to clone.
+///
+/// Publishing never waits: a subscription that falls `SIZE` events behind
+/// skips the oldest and gets `Received::Lagged`, so it can catch up from the
+/// database (e.g. `db::Log::events_after`).
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Arc<Mutex<Vec<Route>>>,
+    missed: Arc<AtomicU64>,
+}
+
+impl Router {
+    /// Events a subscription can fall behind before it starts missing them.
+    pub const SIZE: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Receives the events matching `filter` published from now on. The name
+    /// is only for the logs. The subscription is removed once dropped.
+    pub fn subscribe(&self, name: &str, filter: Filter) -> QueueSubscriber {
+        let (sender, rx) = broadcast::channel(Self::SIZE);
+        self.routes.lock().unwrap().push(Route {
+            name: name.to_owned(),
+            filter,
+            sender,
+        });
+        QueueSubscriber::route(rx, Arc::clone(&self.missed))
+    }
+    /// Events the subscriptions skipped because they were too slow, since the start.
+    pub fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+    /// Sends the event to every subscription whose filter matches. `source`
+    /// is the provider instance it comes from, if known.
+    pub fn publish(&self, source: Option<&str>, event: &Arc<Event>) {
+        self.routes.lock().unwrap().retain(|route| {
+            if route.sender.receiver_count() == 0 {
+                debug!("subscription {:?} dropped", route.name);
+                return false;
+            }
+            if route.filter.matches(source, event) {
+                // Can't fail, there's a receiver.
+                let _ = route.sender.send(Arc::clone(event));
+            }
+            true
+        });
+    }
+    /// Ends every subscription, once they received what was already published.
+    pub fn close(&self) {
+        self.routes.lock().unwrap().clear();
+    }
+}
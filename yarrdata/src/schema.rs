@@ -0,0 +1,92 @@
+//! Versions of the format of the events in the JSONL files.
+//!
+//! Each line records the version it was written in, as `"v"` next to the
+//! event. Older lines are upgraded when read, one version at a time, and lines
+//! from newer versions are read as far as this version understands them.
+use crate::Event;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Version written by this build. Lines without `"v"` are version 1.
+pub const VERSION: u64 = 2;
+
+/// Upgrades from each version to the next one, starting with version 1.
+const MIGRATIONS: [fn(&mut Value); (VERSION - 1) as usize] = [v1_milliseconds];
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    v: u64,
+}
+
+pub fn to_json(event: &Event) -> Result<String> {
+    let versioned = Versioned { event, v: VERSION };
+    Ok(serde_json::to_string(&versioned)?)
+}
+
+/// Reads a line written in any version, and returns the event and that version.
+///
+/// Fields added by newer versions are dropped, so such events can be shown but
+/// not written back without losing something. Events of a kind this version
+/// doesn't know are an error.
+pub fn from_json(text: &str) -> Result<(Event, u64)> {
+    let mut value: Value = serde_json::from_str(text.trim())?;
+    let version = match value.as_object_mut().and_then(|obj| obj.remove("v")) {
+        Some(v) => v.as_u64().context("the schema version isn't a number")?,
+        None => 1,
+    };
+    let first = version.max(1) as usize - 1;
+    for migrate in MIGRATIONS.iter().skip(first) {
+        migrate(&mut value);
+    }
+    match serde_json::from_value(value) {
+        Ok(event) => Ok((event, version)),
+        Err(e) if version > VERSION => bail!(
+            "event written in schema v{}, newer than v{}: {}",
+            version,
+            VERSION,
+            e
+        ),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The version a line was written in, if it's a JSON object.
+pub fn version(text: &str) -> Option<u64> {
+    let value: Value = serde_json::from_str(text.trim()).ok()?;
+    match value.as_object()?.get("v") {
+        Some(v) => v.as_u64(),
+        None => Some(1),
+    }
+}
+
+/// Timestamps below this are in seconds. In milliseconds it's March 1973, and
+/// in seconds it's the year 5138, so no real timestamp can be mistaken.
+const SECONDS_LIMIT: u64 = 100_000_000_000;
+
+/// v1 to v2: timestamps in seconds become milliseconds. Some v1 files already
+/// have milliseconds, which are left as they are.
+fn v1_milliseconds(value: &mut Value) {
+    let event = match value
+        .as_object_mut()
+        .and_then(|obj| obj.values_mut().next())
+    {
+        Some(event) => event,
+        None => return,
+    };
+    to_millis(event.get_mut("timestamp"));
+    if let Some(reply) = event.get_mut("reply_to") {
+        to_millis(reply.get_mut("timestamp"));
+    }
+}
+
+fn to_millis(timestamp: Option<&mut Value>) {
+    if let Some(timestamp) = timestamp {
+        match timestamp.as_u64() {
+            Some(ts) if ts < SECONDS_LIMIT => *timestamp = (ts * 1000).into(),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::default_timestamp;
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderState {
+    /// Opening the first connection.
+    Connecting,
+    /// Connected, logging in.
+    Authenticating,
+    /// Logged in and receiving events.
+    Ready,
+    /// Lost the connection and opening a new one.
+    Reconnecting,
+    /// Gave up or crashed; it may still be restarted.
+    Failed,
+    /// Stopped on request.
+    Stopped,
+}
+
+impl fmt::Display for ProviderState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProviderState::Connecting => "connecting",
+            ProviderState::Authenticating => "authenticating",
+            ProviderState::Ready => "ready",
+            ProviderState::Reconnecting => "reconnecting",
+            ProviderState::Failed => "failed",
+            ProviderState::Stopped => "stopped",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Connection status of a provider. Timestamps are UNIX seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStatus {
+    pub state: ProviderState,
+    /// When `state` last changed.
+    pub since: u64,
+    /// Last time anything was received from the server (messages, pings, syncs...).
+    pub last_activity: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+    /// Connection attempts after the first one.
+    pub reconnects: u32,
+    #[serde(skip)]
+    attempts: u32,
+}
+
+impl ProviderStatus {
+    /// True if the provider claims to be ready but nothing arrived in `max_idle` seconds.
+    pub fn is_stale(&self, now: u64, max_idle: u64) -> bool {
+        let last = self.last_activity.unwrap_or(self.since).max(self.since);
+        self.state == ProviderState::Ready && now.saturating_sub(last) > max_idle
+    }
+}
+
+/// Shared handle to the status of a provider. Clones update the same status,
+/// so it can outlive the client and be handed to the next one after a restart.
+#[derive(Debug, Clone)]
+pub struct StatusHandle {
+    inner: Arc<Mutex<ProviderStatus>>,
+}
+
+impl Default for StatusHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusHandle {
+    pub fn new() -> Self {
+        let status = ProviderStatus {
+            state: ProviderState::Connecting,
+            since: default_timestamp(),
+            last_activity: None,
+            last_error: None,
+            last_error_at: None,
+            reconnects: 0,
+            attempts: 0,
+        };
+        Self {
+            inner: Arc::new(Mutex::new(status)),
+        }
+    }
+    pub fn get(&self) -> ProviderStatus {
+        self.inner.lock().unwrap().clone()
+    }
+    pub fn state(&self) -> ProviderState {
+        self.inner.lock().unwrap().state
+    }
+    /// Returns false if the provider was already in this state.
+    pub fn set_state(&self, state: ProviderState) -> bool {
+        let mut status = self.inner.lock().unwrap();
+        if status.state == state {
+            return false;
+        }
+        status.state = state;
+        status.since = default_timestamp();
+        true
+    }
+    /// Marks the start of a connection attempt. Any attempt after the first
+    /// one counts as a reconnection.
+    pub fn connecting(&self) {
+        let mut status = self.inner.lock().unwrap();
+        if status.attempts > 0 {
+            status.reconnects += 1;
+            status.state = ProviderState::Reconnecting;
+        } else {
+            status.state = ProviderState::Connecting;
+        }
+        status.attempts += 1;
+        status.since = default_timestamp();
+    }
+    pub fn activity(&self) {
+        self.inner.lock().unwrap().last_activity = Some(default_timestamp());
+    }
+    pub fn error(&self, error: impl fmt::Display) {
+        let mut status = self.inner.lock().unwrap();
+        status.last_error = Some(error.to_string());
+        status.last_error_at = Some(default_timestamp());
+    }
+    pub fn fail(&self, error: impl fmt::Display) {
+        self.error(error);
+        self.set_state(ProviderState::Failed);
+    }
+}
@@ -0,0 +1,668 @@
+#![cfg(test)]
+use crate::db::{CachedEvent, EventId, Log, MessageIgnored, Query};
+use crate::fragment::{self, Fragment};
+use crate::router::{Filter, Router};
+use crate::{
+    Author, ChatCleared, Emote, Event, Message, MessageDeleted, MessageEdited, ProviderQueue,
+    QueueSubscriber, Raid, Reaction, ReactionCount, Received, ReplyTo, Role, UserPurged,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A time in milliseconds to count from, to keep the timestamps of the tests short.
+const T0: u64 = 1_650_000_000_000;
+
+/// `timestamp` is in milliseconds after `T0`.
+fn message(timestamp: u64, provider: &str, username: &str, msgid: &str) -> Event {
+    Event::Message(Message {
+        provider_name: provider.to_owned(),
+        room: "#test".to_owned(),
+        message: format!("hello from {}", username),
+        username: username.to_owned(),
+        msgid: msgid.to_owned(),
+        timestamp: T0 + timestamp,
+        ..Default::default()
+    })
+}
+
+/// A folder for the files of a database, removed when dropped, so also when
+/// an assertion fails.
+struct TempLog {
+    dir: std::path::PathBuf,
+    log_path: String,
+    data_path: String,
+}
+
+impl TempLog {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("yarrdata-{}-test-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self {
+            log_path: dir.join("log.jsonl").to_string_lossy().into_owned(),
+            data_path: dir.join("data.jsonl").to_string_lossy().into_owned(),
+            dir,
+        }
+    }
+    /// A database on these files. Opening it again works like a restart.
+    fn open(&self) -> Log {
+        Log::new(100, self.log_path.clone(), self.data_path.clone())
+    }
+}
+
+impl Drop for TempLog {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn test_log(events: Vec<Event>) -> Log {
+    let mut log = Log::new(100, "unused_log.jsonl".into(), "unused_data.jsonl".into());
+    for ev in events {
+        let id = EventId::from_event(&ev);
+        log.data.insert(id, CachedEvent::from_event(ev).unwrap());
+    }
+    log
+}
+
+#[test]
+fn cursor_roundtrip() {
+    let id = EventId::from_event(&message(1650000000, "matrix", "bob", "$abc|def:matrix.org"));
+    let cursor = id.to_cursor();
+    assert!(cursor.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_eq!(EventId::from_cursor(&cursor).unwrap(), id);
+    // Provider names come from the config and can have anything in them.
+    let id = EventId::from_event(&message(1650000000, "demo|x", "bob", "1|2"));
+    assert_eq!(EventId::from_cursor(&id.to_cursor()).unwrap(), id);
+    assert!(EventId::from_cursor("not a cursor").is_err());
+    assert!(EventId::from_cursor(&hex::encode("1|demo|1")).is_err());
+}
+
+#[test]
+fn query_paginates_and_filters() {
+    let log = test_log(vec![
+        message(10, "twitch", "alice", "1"),
+        message(11, "matrix", "bob", "2"),
+        message(12, "twitch", "alice", "3"),
+        message(13, "twitch", "carol", "4"),
+    ]);
+    let mut query = Query {
+        provider: Some("twitch".into()),
+        limit: Some(2),
+        ..Default::default()
+    };
+    let page = log.query(&query).unwrap();
+    let ids: Vec<&str> = page
+        .events
+        .iter()
+        .map(|(id, _)| id.msgid.as_str())
+        .collect();
+    assert_eq!(ids, vec!["1", "3"]);
+    assert!(page.has_more);
+
+    query.cursor = page.next_cursor.map(|id| id.to_cursor());
+    let page = log.query(&query).unwrap();
+    let ids: Vec<&str> = page
+        .events
+        .iter()
+        .map(|(id, _)| id.msgid.as_str())
+        .collect();
+    assert_eq!(ids, vec!["4"]);
+    assert!(!page.has_more);
+
+    let query = Query {
+        username: Some("alice".into()),
+        from_ts: Some(T0 + 11),
+        ..Default::default()
+    };
+    let page = log.query(&query).unwrap();
+    assert_eq!(page.events.len(), 1);
+    assert_eq!(page.events[0].0.msgid, "3");
+}
+
+#[test]
+fn status_counts_reconnects_and_detects_stale() {
+    use crate::status::{ProviderState, StatusHandle};
+    let status = StatusHandle::new();
+    status.connecting();
+    assert_eq!(status.state(), ProviderState::Connecting);
+    assert!(status.set_state(ProviderState::Ready));
+    assert!(!status.set_state(ProviderState::Ready));
+    status.connecting();
+    let st = status.get();
+    assert_eq!(st.state, ProviderState::Reconnecting);
+    assert_eq!(st.reconnects, 1);
+
+    status.set_state(ProviderState::Ready);
+    status.activity();
+    let st = status.get();
+    assert!(!st.is_stale(st.since + 10, 60));
+    assert!(st.is_stale(st.since + 61, 60));
+    status.fail("boom");
+    let st = status.get();
+    assert_eq!(st.last_error.as_deref(), Some("boom"));
+    assert!(!st.is_stale(st.since + 61, 60));
+}
+
+#[tokio::test]
+async fn removals_apply_and_survive_checkpoint() {
+    let temp = TempLog::new("removal");
+
+    let mut log = temp.open();
+    for ev in [
+        message(10, "twitch", "Alice", "1"),
+        message(11, "twitch", "bob", "2"),
+        message(12, "twitch", "Alice", "3"),
+        message(13, "twitch", "carol", "4"),
+    ] {
+        log.push(ev).await.unwrap();
+    }
+    log.push(Event::MessageDeleted(MessageDeleted {
+        provider_name: "twitch".into(),
+        room: "#test".into(),
+        msgid: "del-2".into(),
+        target_msgid: "2".into(),
+        timestamp: T0 + 14,
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    log.push(Event::UserPurged(UserPurged {
+        provider_name: "twitch".into(),
+        room: "#test".into(),
+        username: "alice".into(),
+        msgid: "purge-alice".into(),
+        duration: Some(600),
+        timestamp: T0 + 15,
+    }))
+    .await
+    .unwrap();
+    let left: Vec<&str> = log.data.keys().map(|id| id.msgid.as_str()).collect();
+    assert_eq!(left, vec!["4"]);
+    // The removals are still stored, for the readers that show the messages.
+    let stored: Vec<&str> = log
+        .events_after(None)
+        .map(|(id, _)| id.msgid.as_str())
+        .collect();
+    assert_eq!(stored, vec!["4", "del-2", "purge-alice"]);
+
+    log.perform_checkpoint().await.unwrap();
+    let mut log = temp.open();
+    log.load().await.unwrap();
+    // Seen again, e.g. after a reconnection: it has to stay removed.
+    let (again, _) = log.push(message(11, "twitch", "bob", "2")).await.unwrap();
+    assert!(matches!(again, MessageIgnored::Deleted));
+    // Alice can talk again after the timeout.
+    let (later, _) = log.push(message(16, "twitch", "Alice", "5")).await.unwrap();
+    assert!(matches!(later, MessageIgnored::None));
+
+    log.push(Event::ChatCleared(ChatCleared {
+        provider_name: "twitch".into(),
+        room: "#test".into(),
+        msgid: "clear".into(),
+        timestamp: T0 + 17,
+    }))
+    .await
+    .unwrap();
+    assert!(log.data.is_empty());
+
+    // Removals don't take the place of messages in a full database.
+    for n in 0..100 {
+        let id = format!("m{}", n);
+        log.push(message(20 + n, "twitch", "dave", &id))
+            .await
+            .unwrap();
+    }
+    for n in 0..10 {
+        log.push(Event::MessageDeleted(MessageDeleted {
+            provider_name: "twitch".into(),
+            room: "#test".into(),
+            msgid: format!("del-x{}", n),
+            target_msgid: format!("x{}", n),
+            timestamp: T0 + 200 + n,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    }
+    assert_eq!(log.data.len(), 100);
+}
+
+#[tokio::test]
+async fn edits_update_the_stored_message() {
+    let temp = TempLog::new("edit");
+
+    let mut log = temp.open();
+    let original = message(10, "matrix", "bob", "$orig");
+    log.push(original.clone()).await.unwrap();
+    log.push(Event::MessageEdited(MessageEdited {
+        provider_name: "matrix".into(),
+        room: "#test".into(),
+        username: "bob".into(),
+        msgid: "$edit".into(),
+        target_msgid: "$orig".into(),
+        message: "fixed typo".into(),
+        fragments: vec![],
+        timestamp: T0 + 20,
+    }))
+    .await
+    .unwrap();
+    let stored = &log.data[&EventId::from_event(&original)];
+    assert!(matches!(&stored.event, Event::Message(m) if m.message == "fixed typo"));
+    assert!(stored.json.contains("fixed typo"));
+
+    // Loading the files again, the edit is applied to the message again.
+    let mut log = temp.open();
+    log.load().await.unwrap();
+    let stored = &log.data[&EventId::from_event(&original)];
+    assert!(matches!(&stored.event, Event::Message(m) if m.message == "fixed typo"));
+
+    // Edits don't take the place of messages, even the oldest one's.
+    for n in 0..99 {
+        let msgid = format!("m{}", n);
+        log.push(message(30 + n, "matrix", "dave", &msgid))
+            .await
+            .unwrap();
+    }
+    assert_eq!(log.data.len(), 100);
+    for n in 0..20 {
+        log.push(Event::MessageEdited(MessageEdited {
+            provider_name: "matrix".into(),
+            room: "#test".into(),
+            username: "bob".into(),
+            msgid: format!("$edit{}", n),
+            target_msgid: "$orig".into(),
+            message: format!("take {}", n),
+            fragments: vec![],
+            timestamp: T0 + 200 + n,
+        }))
+        .await
+        .unwrap();
+    }
+    assert_eq!(log.data.len(), 100);
+    let stored = &log.data[&EventId::from_event(&original)];
+    assert!(matches!(&stored.event, Event::Message(m) if m.message == "take 19"));
+    // They go away with their message.
+    log.push(message(300, "matrix", "dave", "last"))
+        .await
+        .unwrap();
+    assert!(!log.data.contains_key(&EventId::from_event(&original)));
+    assert!(log
+        .events_after(None)
+        .all(|(_, ce)| !matches!(ce.event, Event::MessageEdited(_))));
+}
+
+#[test]
+fn old_messages_deserialize_without_author() {
+    let line = r##"{"Message":{"provider_name":"twitch","room":"#test","message":"hi","username":"Alice","msgid":"1","timestamp":10,"badges":[],"emotes":[]}}"##;
+    let ev = Event::from_json(line).unwrap();
+    let author = ev.author().unwrap();
+    assert_eq!(author, &Author::default());
+    assert_eq!(ev.username(), "Alice");
+
+    let mut msg = match message(11, "twitch", "Bob", "2") {
+        Event::Message(m) => m,
+        _ => unreachable!(),
+    };
+    msg.author = Author {
+        id: "1234".into(),
+        login: "bob".into(),
+        display_name: "Bob".into(),
+        roles: vec![Role::Moderator],
+        ..Default::default()
+    };
+    let log = test_log(vec![ev, Event::Message(msg)]);
+    let query = Query {
+        user_id: Some("1234".into()),
+        ..Default::default()
+    };
+    let page = log.query(&query).unwrap();
+    assert_eq!(page.events.len(), 1);
+    assert!(page.events[0].1.author().unwrap().has_role(Role::Moderator));
+}
+
+#[test]
+fn tokenize_splits_emotes_mentions_and_links() {
+    let text = "¡hola @Bob, Kappa see https://example.org.";
+    let emotes = vec![Emote {
+        id: "25".into(),
+        from: 12,
+        to: 16,
+        name: "Kappa".into(),
+        url: "https://example.org/25.png".into(),
+    }];
+    let fragments = fragment::tokenize(text, &emotes, &[]);
+    assert_eq!(
+        fragments,
+        vec![
+            Fragment::Text {
+                text: "¡hola ".into()
+            },
+            Fragment::Mention {
+                text: "@Bob".into(),
+                username: "Bob".into()
+            },
+            Fragment::Text { text: ", ".into() },
+            Fragment::Emote {
+                text: "Kappa".into(),
+                id: "25".into(),
+                url: "https://example.org/25.png".into()
+            },
+            Fragment::Text {
+                text: " see ".into()
+            },
+            Fragment::Link {
+                text: "https://example.org".into(),
+                url: "https://example.org".into()
+            },
+            Fragment::Text { text: ".".into() },
+        ]
+    );
+    let joined: String = fragments.iter().map(|f| f.text()).collect();
+    assert_eq!(joined, text);
+
+    // Positions past the end of the text are ignored instead of panicking.
+    let emotes = vec![Emote {
+        from: 40,
+        to: 44,
+        ..emotes[0].clone()
+    }];
+    assert_eq!(
+        fragment::tokenize("Kappa", &emotes, &[]),
+        vec![Fragment::Text {
+            text: "Kappa".into()
+        }]
+    );
+}
+
+#[tokio::test]
+async fn replies_are_linked_to_their_parent() {
+    let temp = TempLog::new("reply");
+
+    let mut log = temp.open();
+    let parent = message(10, "twitch", "alice", "p1");
+    log.push(parent.clone()).await.unwrap();
+
+    let mut reply = match message(20, "twitch", "bob", "r1") {
+        Event::Message(m) => m,
+        _ => unreachable!(),
+    };
+    reply.reply_to = Some(Box::new(ReplyTo {
+        msgid: "p1".into(),
+        ..Default::default()
+    }));
+    // What's published downstream is the event as stored.
+    let (_, stored) = log.push(Event::Message(reply)).await.unwrap();
+    let stored = match stored.as_ref() {
+        Event::Message(m) => m.clone(),
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        EventId::reply_parent(&stored),
+        Some(EventId::from_event(&parent))
+    );
+    assert_eq!(stored.reply_to.unwrap().snippet, "hello from alice");
+
+    let long = "x".repeat(ReplyTo::SNIPPET_LEN + 5);
+    let snippet = ReplyTo::snippet_of(&long);
+    assert_eq!(snippet.chars().count(), ReplyTo::SNIPPET_LEN + 1);
+    assert!(snippet.ends_with('…'));
+}
+
+fn reaction(timestamp: u64, user: &str, msgid: &str, key: &str) -> Event {
+    Event::Reaction(Reaction {
+        provider_name: "matrix".into(),
+        room: "#test".into(),
+        username: user.into(),
+        user_id: format!("@{}:example.org", user),
+        msgid: msgid.into(),
+        target_msgid: "$msg".into(),
+        key: key.into(),
+        timestamp: T0 + timestamp,
+    })
+}
+
+#[tokio::test]
+async fn reactions_are_counted_per_message() {
+    let temp = TempLog::new("reaction");
+
+    let mut log = temp.open();
+    let msg = message(10, "matrix", "bob", "$msg");
+    let id = EventId::from_event(&msg);
+    log.push(msg).await.unwrap();
+    log.push(reaction(11, "alice", "$r1", "👍")).await.unwrap();
+    log.push(reaction(12, "carol", "$r2", "👍")).await.unwrap();
+    log.push(reaction(13, "carol", "$r3", "🎉")).await.unwrap();
+    // The same user can't count twice for the same key.
+    log.push(reaction(14, "alice", "$r4", "👍")).await.unwrap();
+    // Carol takes her 👍 back.
+    log.push(Event::MessageDeleted(MessageDeleted {
+        provider_name: "matrix".into(),
+        room: "#test".into(),
+        msgid: "$redact".into(),
+        target_msgid: "$r2".into(),
+        timestamp: T0 + 15,
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+
+    let expected = vec![
+        ReactionCount {
+            key: "👍".into(),
+            count: 1,
+            users: vec!["alice".into()],
+        },
+        ReactionCount {
+            key: "🎉".into(),
+            count: 1,
+            users: vec!["carol".into()],
+        },
+    ];
+    let reactions = |log: &Log| match &log.data[&id].event {
+        Event::Message(m) => m.reactions.clone(),
+        _ => unreachable!(),
+    };
+    assert_eq!(reactions(&log), expected);
+
+    log.perform_checkpoint().await.unwrap();
+    let mut log = temp.open();
+    log.load().await.unwrap();
+    assert_eq!(reactions(&log), expected);
+
+    // Reactions don't take the place of messages: 99 more fill the database.
+    for n in 0..99 {
+        let msgid = format!("m{}", n);
+        log.push(message(20 + n, "matrix", "dave", &msgid))
+            .await
+            .unwrap();
+    }
+    assert_eq!(log.data.len(), 100);
+    assert_eq!(reactions(&log), expected);
+    // And they go away with their message.
+    log.push(message(200, "matrix", "dave", "last"))
+        .await
+        .unwrap();
+    assert!(!log.data.contains_key(&id));
+    assert!(log
+        .events_after(None)
+        .all(|(_, ce)| !matches!(ce.event, Event::Reaction(_))));
+}
+
+#[tokio::test]
+async fn seconds_are_migrated_to_milliseconds() {
+    let temp = TempLog::new("ms");
+    let old = concat!(
+        r#"{"Message":{"provider_name":"twitch","msgid":"1","timestamp":1650000000}}"#,
+        "\n",
+        r#"{"Message":{"provider_name":"twitch","msgid":"2","timestamp":1650000001,"reply_to":{"msgid":"1","timestamp":1650000000}}}"#,
+        "\n",
+    );
+    std::fs::write(&temp.data_path, old).unwrap();
+    // Already in milliseconds: left alone.
+    let new = message(5000, "matrix", "bob", "3").to_json().unwrap();
+    std::fs::write(&temp.log_path, &new).unwrap();
+
+    let mut log = temp.open();
+    log.load().await.unwrap();
+    let timestamps: Vec<u64> = log.data.keys().map(|id| id.timestamp).collect();
+    assert_eq!(timestamps, vec![1650000000000, 1650000001000, T0 + 5000]);
+    let reply = log.data.values().find_map(|ce| match &ce.event {
+        Event::Message(m) if m.msgid == "2" => EventId::reply_parent(m),
+        _ => None,
+    });
+    assert_eq!(reply.unwrap().timestamp, 1650000000000);
+
+    log.perform_checkpoint().await.unwrap();
+    let backup = std::fs::read_to_string(format!("{}.v1.bak", temp.data_path)).unwrap();
+    assert_eq!(backup, old);
+    let rewritten = std::fs::read_to_string(&temp.data_path).unwrap();
+    assert!(rewritten.contains("1650000001000"));
+}
+
+#[test]
+fn schema_versions() {
+    use crate::schema;
+    let line = message(1, "twitch", "alice", "1").to_json().unwrap();
+    // The event goes first, readers of the files look at the first key.
+    assert!(line.starts_with(r#"{"Message":"#));
+    assert!(line
+        .trim_end()
+        .ends_with(&format!(r#","v":{}}}"#, schema::VERSION)));
+    let (_, version) = schema::from_json(&line).unwrap();
+    assert_eq!(version, schema::VERSION);
+
+    // Newer versions are read as far as possible.
+    let newer = r#"{"Message":{"msgid":"9","timestamp":1650000000000,"shiny":true},"v":99}"#;
+    let (event, version) = schema::from_json(newer).unwrap();
+    assert_eq!((event.msgid(), version), ("9", 99));
+    let unknown = r#"{"Poll":{"question":"?"},"v":99}"#;
+    let err = schema::from_json(unknown).unwrap_err();
+    assert!(err.to_string().contains("newer"));
+    assert_eq!(schema::version(unknown), Some(99));
+}
+
+#[tokio::test]
+async fn provider_queues_wait_for_a_slow_subscriber() {
+    let mut queue = ProviderQueue::with_capacity("twitch".to_owned(), 2);
+    let mut sub = queue.subscribe();
+    for n in 0..2 {
+        queue
+            .publish(message(n, "twitch", "bob", &n.to_string()))
+            .await
+            .unwrap();
+    }
+    // Full, so the next one waits instead of pushing out the oldest.
+    let first = {
+        let third = queue.publish(message(2, "twitch", "bob", "2"));
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut third)
+            .await
+            .is_err());
+        assert_eq!(queue.stats.depth(), 2);
+        let first = sub.recv().await;
+        third.await.unwrap();
+        first
+    };
+    let mut msgids = vec![];
+    for received in [first, sub.recv().await, sub.recv().await] {
+        match received {
+            Received::Event(ev) => msgids.push(ev.msgid().to_owned()),
+            other => panic!("expected an event, got {:?}", other),
+        }
+        queue.stats.consumed();
+    }
+    assert_eq!(msgids, ["0", "1", "2"]);
+    assert_eq!(queue.stats.depth(), 0);
+    assert!(sub.try_recv().is_none());
+    queue.close();
+    assert!(matches!(sub.recv().await, Received::Closed));
+    assert!(queue
+        .publish(message(6, "twitch", "bob", "6"))
+        .await
+        .is_err());
+    assert!(matches!(queue.subscribe().recv().await, Received::Closed));
+
+    // Router subscriptions don't hold the others back, they skip and resync.
+    let router = Router::new();
+    let mut slow = router.subscribe("slow", Filter::default());
+    for n in 0..Router::SIZE as u64 + 3 {
+        router.publish(None, &Arc::new(message(n, "twitch", "bob", "x")));
+    }
+    assert!(matches!(slow.recv().await, Received::Lagged(3)));
+    assert_eq!(router.missed(), 3);
+}
+
+#[tokio::test]
+async fn router_sends_each_subscription_what_its_filter_matches() {
+    let filter: Filter = serde_json::from_value(serde_json::json!({
+        "providers": ["twitch.main"],
+        "kinds": ["message"],
+        "text": "(?i)^hello",
+        "exclude": { "roles": ["moderator"] },
+    }))
+    .unwrap();
+    assert!(serde_json::from_str::<Filter>(r#"{"text": "("}"#).is_err());
+    assert!(serde_json::from_str::<Filter>(r#"{"kinds": ["nope"]}"#).is_err());
+
+    let router = Router::new();
+    let mut chat = router.subscribe("chat", filter);
+    let mut all = router.subscribe("all", Filter::default());
+    let mut moderator = message(1, "twitch", "mod", "1");
+    if let Event::Message(m) = &mut moderator {
+        m.author.roles = vec![Role::Moderator];
+    }
+    let raid = Event::Raid(Raid {
+        provider_name: "twitch".to_owned(),
+        msgid: "2".to_owned(),
+        ..Default::default()
+    });
+    for (source, ev) in [
+        ("twitch.main", message(0, "twitch", "bob", "0")),
+        ("twitch.main", moderator),
+        ("twitch.main", raid),
+        ("twitch.other", message(3, "twitch", "eve", "3")),
+    ] {
+        router.publish(Some(source), &Arc::new(ev));
+    }
+    router.close();
+    let received = |sub: &mut QueueSubscriber| {
+        let mut msgids = vec![];
+        while let Some(Received::Event(ev)) = sub.try_recv() {
+            msgids.push(ev.msgid().to_owned());
+        }
+        msgids
+    };
+    assert_eq!(received(&mut chat), ["0"]);
+    assert_eq!(received(&mut all), ["0", "1", "2", "3"]);
+    // Stored events don't know their instance, only the provider name.
+    let by_name: Filter = serde_json::from_str(r#"{"providers": ["twitch"]}"#).unwrap();
+    assert!(by_name.matches(None, &message(4, "twitch", "bob", "4")));
+    assert!(!by_name.matches(None, &message(4, "matrix", "bob", "4")));
+
+    // Only messages are asked for, but those already received can still go away.
+    let router = Router::new();
+    let mut messages = router.subscribe(
+        "messages",
+        serde_json::from_str(r##"{"kinds": ["message"], "rooms": ["#test"]}"##).unwrap(),
+    );
+    let deletion = |room: &str| {
+        Event::MessageDeleted(MessageDeleted {
+            provider_name: "twitch".into(),
+            room: room.into(),
+            msgid: format!("del-5{}", room),
+            target_msgid: "5".into(),
+            timestamp: T0 + 6,
+            ..Default::default()
+        })
+    };
+    for ev in [
+        message(5, "twitch", "bob", "5"),
+        deletion("#test"),
+        deletion("#elsewhere"),
+    ] {
+        router.publish(None, &Arc::new(ev));
+    }
+    router.close();
+    assert_eq!(received(&mut messages), ["5", "del-5#test"]);
+}
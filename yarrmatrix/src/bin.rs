@@ -1,37 +1,75 @@
-use anyhow::Result;
-use futures::StreamExt;
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
 use log::LevelFilter;
 use std::borrow::Borrow;
-use yarrcfg::parse_config;
-use yarrdata::Event;
+use yarrcfg::{set_log_level, ConfigArgs, ConfigLoader};
+use yarrdata::provider::Provider;
+use yarrdata::{Event, Received};
+
+/// Connects to Matrix with the config of yarrosco and prints the messages received.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    cfg: ConfigArgs,
+    /// Which [matrix.NAME] section to use [default: the first one]
+    #[clap(long)]
+    name: Option<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    set_log_level(args.cfg.log_level.as_deref());
     env_logger::builder()
         .filter(Some("sled"), LevelFilter::Info)
         .filter(Some("reqwest"), LevelFilter::Info)
         .init();
 
-    let cfg = parse_config()?;
-    let (_, matrix_cfg) = cfg
-        .matrix
-        .iter()
-        .next()
-        .expect("Matrix config is needed to run IRC");
-    let mut mx = yarrmatrix::MatrixClient::new(matrix_cfg)?;
+    let cfg = ConfigLoader::from_args(&args.cfg).load()?;
+    let matrix_cfg = match &args.name {
+        Some(name) => cfg.matrix.get(name).ok_or_else(|| {
+            let sections: Vec<String> = cfg
+                .matrix
+                .keys()
+                .map(|n| format!("[matrix.{}]", n))
+                .collect();
+            if sections.is_empty() {
+                anyhow!(
+                    "there's no [matrix.{}] in the config, nor any other [matrix.NAME]",
+                    name
+                )
+            } else {
+                anyhow!(
+                    "there's no [matrix.{}] in the config, only {}",
+                    name,
+                    sections.join(", ")
+                )
+            }
+        })?,
+        None => cfg
+            .matrix
+            .values()
+            .next()
+            .context("a [matrix.NAME] section is needed to run")?,
+    };
+    let mut mx = yarrmatrix::MatrixClient::new(matrix_cfg).await?;
 
     let mut matrix_sub = mx.subscribe();
     let mx_future = tokio::task::spawn(async move { mx.run().await });
 
-    while let Some(ev) = matrix_sub.next().await {
+    loop {
+        let ev = match matrix_sub.recv().await {
+            Received::Event(ev) => ev,
+            // Provider queues wait for the subscriber instead of skipping events.
+            Received::Lagged(_) | Received::Closed => break,
+        };
         // Upon receiving a new message...
         match ev.borrow() {
             Event::Message(m) => {
                 println!(">> {:?}>> {}", std::thread::current().id(), m.message)
             }
+            ev => println!(">> {:?}>> {:?}", std::thread::current().id(), ev),
         }
     }
     mx_future.await??;
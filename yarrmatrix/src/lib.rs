@@ -1,117 +1,220 @@
 use anyhow::{Context, Result};
-use bus_queue::Subscriber;
+use async_trait::async_trait;
 use log::{debug, error, info};
 use matrix_sdk::{
     room::Room,
     ruma::events::{
-        room::message::{MessageEventContent, MessageType},
+        reaction::ReactionEventContent,
+        room::message::{MessageEventContent, MessageType, Relation},
+        room::redaction::SyncRedactionEvent,
         SyncMessageEvent,
     },
     ruma::UserId,
-    Client, SyncSettings,
+    Client, LoopCtrl, SyncSettings,
 };
 use ruma_identifiers::DeviceId;
-use yarrdata::{Event, Message, ProviderQueue, SyncSubscriber};
+use yarrdata::provider::Provider;
+use yarrdata::status::{ProviderState, StatusHandle};
+use yarrdata::{
+    stop_channel, Author, Event, Message, MessageDeleted, MessageEdited, ProviderQueue, QueueStats,
+    QueueSubscriber, Reaction, ReplyTo, Role, StopHandle, StopSignal,
+};
+
+/// Room events passed from the sync task to `sync_messages`.
+enum Received {
+    /// The message, its author and the author of the message it replies to.
+    Message(
+        SyncMessageEvent<MessageEventContent>,
+        Room,
+        Author,
+        Option<Author>,
+    ),
+    Reaction(SyncMessageEvent<ReactionEventContent>, Room, Author),
+    Redaction(SyncRedactionEvent, Room),
+}
 
 pub struct MatrixClient {
     session: matrix_sdk::Session,
     target_room: String,
-    ready: bool,
+    status: StatusHandle,
     queue: ProviderQueue,
+    stop: StopSignal,
+    stop_handle: StopHandle,
 }
 
 impl MatrixClient {
-    pub fn new(matrix_cfg: &yarrcfg::Matrix) -> Result<Self> {
-        let user = UserId::try_from(matrix_cfg.user_id.clone())?;
-        let session = matrix_sdk::Session {
-            access_token: matrix_cfg.access_token.0.clone(),
-            user_id: user,
-            device_id: DeviceId::new(),
-        };
-
-        let target_room = matrix_cfg.room_id.clone();
-        Ok(Self {
-            session,
-            target_room,
-            ready: false,
-            queue: ProviderQueue::new("matrix".to_owned()),
-        })
-    }
-    pub fn subscribe(&self) -> Subscriber<Event> {
-        self.queue.subscribe()
+    pub fn status_handle(&self) -> StatusHandle {
+        self.status.clone()
     }
-    pub fn subscribe_sync(&self) -> SyncSubscriber {
-        self.queue.subscribe_sync()
-    }
-    pub async fn run(&mut self) -> Result<()> {
-        self.ready = false;
+    async fn sync_messages(&mut self) -> Result<()> {
+        self.status.connecting();
         let client = Client::new_from_user_id(self.session.user_id.clone()).await?;
         debug!("authenticating as {:?}", &self.session.user_id);
+        self.status.set_state(ProviderState::Authenticating);
         client.restore_login(self.session.clone()).await?;
         info!("waiting for messages");
-        let (tx, rx) = flume::unbounded::<(SyncMessageEvent<MessageEventContent>, Room)>();
+        let (tx, rx) = flume::unbounded::<Received>();
+        let status = self.status.clone();
+        let homeserver = client.homeserver().await.to_string();
         let jh = tokio::task::spawn(async move {
+            let redaction_tx = tx.clone();
+            let reaction_tx = tx.clone();
+            let reaction_homeserver = homeserver.clone();
             client
                 .register_event_handler(
                     move |ev: SyncMessageEvent<MessageEventContent>, room: Room| {
-                        tx.send((ev, room)).unwrap();
-                        async {}
+                        let tx = tx.clone();
+                        let homeserver = homeserver.clone();
+                        async move {
+                            // Looking up the member can take a request to the server,
+                            // so it's done here instead of in the loop receiving them.
+                            let author = author(&room, &ev.sender, &homeserver).await;
+                            let parent = reply_author(&room, &ev, &homeserver).await;
+                            tx.send(Received::Message(ev, room, author, parent))
+                                .unwrap();
+                        }
+                    },
+                )
+                .await
+                .register_event_handler(
+                    move |ev: SyncMessageEvent<ReactionEventContent>, room: Room| {
+                        let tx = reaction_tx.clone();
+                        let homeserver = reaction_homeserver.clone();
+                        async move {
+                            let author = author(&room, &ev.sender, &homeserver).await;
+                            tx.send(Received::Reaction(ev, room, author)).unwrap();
+                        }
                     },
                 )
+                .await
+                .register_event_handler(move |ev: SyncRedactionEvent, room: Room| {
+                    redaction_tx.send(Received::Redaction(ev, room)).unwrap();
+                    async {}
+                })
                 .await;
             // Syncing is important to synchronize the client state with the server.
-            // This method will never return.
-            client.sync(SyncSettings::default()).await;
+            // This method will never return. Failed syncs are retried internally,
+            // so the time of the last sync is what tells if it's still working.
+            client
+                .sync_with_callback(SyncSettings::default(), move |_| {
+                    let status = status.clone();
+                    async move {
+                        if status.set_state(ProviderState::Ready) {
+                            info!("matrix sync running");
+                        }
+                        status.activity();
+                        LoopCtrl::Continue
+                    }
+                })
+                .await;
         });
+        let mut stop = self.stop.clone();
         loop {
-            match rx.recv_async().await {
-                Ok((e, r)) => {
-                    if let Err(err) = self.process_message_sync(e, r) {
+            let received = tokio::select! {
+                received = rx.recv_async() => received,
+                _ = stop.stopped() => {
+                    info!("stopping matrix sync");
+                    // Syncing is just HTTP long polling, there's no connection to close.
+                    jh.abort();
+                    break;
+                }
+            };
+            match received {
+                Ok(Received::Message(e, r, author, parent)) => {
+                    if let Err(err) = self.process_message(e, r, author, parent).await {
                         error!("error processing message: {:?}", err);
                     }
                 }
+                Ok(Received::Reaction(e, r, author)) => {
+                    if let Err(err) = self.process_reaction(e, r, author).await {
+                        error!("error processing reaction: {:?}", err);
+                    }
+                }
+                Ok(Received::Redaction(e, r)) => {
+                    if let Err(err) = self.process_redaction(e, r).await {
+                        error!("error processing redaction: {:?}", err);
+                    }
+                }
                 Err(e) => {
                     error!("error receiving messages from matrix client channel (might indicate connection closed): {:?}", e);
                     break;
                 }
             }
         }
-        self.ready = false;
-        jh.await?;
-
+        match jh.await {
+            Err(e) if e.is_cancelled() => {}
+            r => r?,
+        }
         Ok(())
     }
 
-    fn process_message_sync(
+    async fn process_message(
         &mut self,
         ev: SyncMessageEvent<MessageEventContent>,
         room: Room,
+        author: Author,
+        parent: Option<Author>,
     ) -> Result<()> {
-        if !self.ready {
-            self.ready = true;
-            info!("receiving messages");
-        }
+        self.status.activity();
         let room_id = room.room_id().as_str();
         if self.target_room != room_id {
             debug!("Ignored message from room ID {:?}", room_id);
         } else {
             debug!("Room {:?} >> Received a message {:?}", room.name(), ev);
             let msgid = ev.event_id.to_string();
-            let username = ev.sender.localpart().to_owned();
-            let timestamp: u64 = ev.origin_server_ts.as_secs().into();
+            let username = author.display_name.clone();
+            let timestamp: u64 = ev.origin_server_ts.get().into();
+            // Edits also carry a "* new text" body as a fallback, which we don't want.
+            if let Some(Relation::Replacement(replacement)) = ev.content.relates_to {
+                if let MessageType::Text(msg) = replacement.new_content.msgtype {
+                    self.queue
+                        .publish(Event::MessageEdited(MessageEdited {
+                            provider_name: self.queue.provider_name.clone(),
+                            room: room.name().unwrap_or_default(),
+                            username,
+                            msgid,
+                            target_msgid: replacement.event_id.to_string(),
+                            message: msg.body.clone(),
+                            fragments: vec![],
+                            timestamp,
+                        }))
+                        .await
+                        .with_context(|| {
+                            format!("trying to publish to the queue the edit {:?}", msg.body)
+                        })?;
+                }
+                return Ok(());
+            }
             if let MessageType::Text(msg) = ev.content.msgtype {
-                // This might block, but the type of queue promises no blocking
-                // and anyway this is the only thing we do in this loop.
+                let mut text = msg.body.as_str();
+                let mut reply_to = None;
+                // Threads aren't known to this version of the SDK, but thread
+                // messages also reply to the previous one in the thread.
+                if let Some(Relation::Reply { in_reply_to }) = &ev.content.relates_to {
+                    let mut reply = ReplyTo {
+                        msgid: in_reply_to.event_id.to_string(),
+                        author: parent.unwrap_or_default(),
+                        ..Default::default()
+                    };
+                    if let Some((_, quote, rest)) = split_reply_fallback(text) {
+                        reply.snippet = ReplyTo::snippet_of(&quote);
+                        text = rest;
+                    }
+                    reply_to = Some(Box::new(reply));
+                }
                 self.queue
-                    .publish_sync(Event::Message(Message {
+                    .publish(Event::Message(Message {
                         provider_name: self.queue.provider_name.clone(),
-                        message: msg.body.clone(),
+                        message: text.to_owned(),
                         room: room.name().unwrap_or_default(),
                         username,
+                        author,
                         msgid,
                         timestamp,
+                        reply_to,
                         ..Default::default()
                     }))
+                    .await
                     .with_context(|| {
                         format!("trying to publish to the queue the message {:?}", msg.body)
                     })?;
@@ -119,4 +222,196 @@ impl MatrixClient {
         }
         Ok(())
     }
+    /// Someone reacted to a message. Taking it back is a redaction of this event.
+    async fn process_reaction(
+        &mut self,
+        ev: SyncMessageEvent<ReactionEventContent>,
+        room: Room,
+        author: Author,
+    ) -> Result<()> {
+        self.status.activity();
+        let room_id = room.room_id().as_str();
+        if self.target_room != room_id {
+            debug!("Ignored reaction from room ID {:?}", room_id);
+            return Ok(());
+        }
+        debug!("Room {:?} >> Received a reaction {:?}", room.name(), ev);
+        let relation = ev.content.relates_to;
+        self.queue
+            .publish(Event::Reaction(Reaction {
+                provider_name: self.queue.provider_name.clone(),
+                room: room.name().unwrap_or_default(),
+                username: author.display_name,
+                user_id: author.id,
+                msgid: ev.event_id.to_string(),
+                target_msgid: relation.event_id.to_string(),
+                key: relation.emoji.clone(),
+                timestamp: ev.origin_server_ts.get().into(),
+            }))
+            .await
+            .with_context(|| {
+                format!(
+                    "trying to publish to the queue the reaction {:?}",
+                    relation.emoji
+                )
+            })?;
+        Ok(())
+    }
+    /// A message (or any other event) was removed from the room.
+    async fn process_redaction(&mut self, ev: SyncRedactionEvent, room: Room) -> Result<()> {
+        self.status.activity();
+        let room_id = room.room_id().as_str();
+        if self.target_room != room_id {
+            debug!("Ignored redaction from room ID {:?}", room_id);
+            return Ok(());
+        }
+        debug!("Room {:?} >> Received a redaction {:?}", room.name(), ev);
+        let target_msgid = ev.redacts.to_string();
+        self.queue
+            .publish(Event::MessageDeleted(MessageDeleted {
+                provider_name: self.queue.provider_name.clone(),
+                room: room.name().unwrap_or_default(),
+                // The sender is who redacted it, not necessarily the author.
+                username: String::new(),
+                msgid: ev.event_id.to_string(),
+                target_msgid,
+                timestamp: ev.origin_server_ts.get().into(),
+            }))
+            .await
+            .with_context(|| {
+                format!(
+                    "trying to publish to the queue the redaction {:?}",
+                    ev.event_id
+                )
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Provider for MatrixClient {
+    type Config = yarrcfg::Matrix;
+
+    async fn new(matrix_cfg: &yarrcfg::Matrix) -> Result<Self> {
+        let user = UserId::try_from(matrix_cfg.user_id.clone())?;
+        let session = matrix_sdk::Session {
+            access_token: matrix_cfg.access_token.0.clone(),
+            user_id: user,
+            device_id: DeviceId::new(),
+        };
+
+        let target_room = matrix_cfg.room_id.clone();
+        let (stop_handle, stop) = stop_channel();
+        Ok(Self {
+            session,
+            target_room,
+            status: StatusHandle::new(),
+            queue: ProviderQueue::new("matrix".to_owned()),
+            stop,
+            stop_handle,
+        })
+    }
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+    fn subscribe(&self) -> QueueSubscriber {
+        self.queue.subscribe()
+    }
+    fn set_status_handle(&mut self, status: StatusHandle) {
+        self.status = status;
+    }
+    fn set_queue_stats(&mut self, stats: QueueStats) {
+        self.queue.stats = stats;
+    }
+    async fn run(&mut self) -> Result<()> {
+        let result = self.sync_messages().await;
+        match &result {
+            Ok(()) if self.stop.is_stopped() => {
+                self.status.set_state(ProviderState::Stopped);
+            }
+            Ok(()) => self.status.fail("matrix sync ended"),
+            Err(e) => self.status.fail(format!("{:#}", e)),
+        }
+        // Ends the subscriber streams, so consumers know no more events will come.
+        self.queue.close();
+        result
+    }
+}
+
+/// Who sent an event, with the name and avatar the user has in the room.
+async fn author(room: &Room, user_id: &UserId, homeserver: &str) -> Author {
+    let mut author = Author {
+        id: user_id.to_string(),
+        login: user_id.localpart().to_owned(),
+        display_name: user_id.localpart().to_owned(),
+        ..Default::default()
+    };
+    match room.get_member(user_id).await {
+        Ok(Some(member)) => {
+            if let Some(name) = member.display_name() {
+                author.display_name = name.to_owned();
+            }
+            if let Some(avatar) = member.avatar_url() {
+                author.avatar_url = media_url(homeserver, avatar.as_str());
+            }
+            // 50 is the default level for moderators in Matrix.
+            if member.power_level() >= 50 {
+                author.roles.push(Role::Moderator);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => debug!("couldn't get the room member {:?}: {:?}", user_id, e),
+    }
+    author
+}
+
+/// Author of the message a reply answers to, going by the quote of the parent
+/// that replies start with.
+async fn reply_author(
+    room: &Room,
+    ev: &SyncMessageEvent<MessageEventContent>,
+    homeserver: &str,
+) -> Option<Author> {
+    let body = match (&ev.content.relates_to, &ev.content.msgtype) {
+        (Some(Relation::Reply { .. }), MessageType::Text(msg)) => &msg.body,
+        _ => return None,
+    };
+    let (sender, _, _) = split_reply_fallback(body)?;
+    let user_id = UserId::try_from(sender).ok()?;
+    Some(author(room, &user_id, homeserver).await)
+}
+
+/// Replies start with a quote of the parent for clients that don't show replies:
+/// "> <@alice:example.org> the parent\n> more of it\n\nthe reply".
+/// Returns the sender and text of the quote, and the reply without it.
+fn split_reply_fallback(body: &str) -> Option<(&str, String, &str)> {
+    let mut quoted = vec![];
+    let mut end = 0;
+    for line in body.split_inclusive('\n') {
+        match line.strip_prefix('>') {
+            Some(quote) => quoted.push(quote.trim()),
+            None => break,
+        }
+        end += line.len();
+    }
+    let first: &str = quoted.first()?;
+    let (sender, first) = first.strip_prefix('<')?.split_once('>')?;
+    quoted[0] = first.trim();
+    Some((
+        sender,
+        quoted.join(" "),
+        body[end..].trim_start_matches('\n'),
+    ))
+}
+
+/// HTTP URL of a small thumbnail for an `mxc://` URI, for the overlay to load.
+fn media_url(homeserver: &str, mxc: &str) -> String {
+    match mxc.strip_prefix("mxc://") {
+        Some(path) => format!(
+            "{}/_matrix/media/r0/thumbnail/{}?width=64&height=64&method=crop",
+            homeserver.trim_end_matches('/'),
+            path
+        ),
+        None => mxc.to_owned(),
+    }
 }
@@ -0,0 +1,426 @@
+//! A provider that makes up a chat, to work on the overlay, its themes and the
+//! renderers without a live one. Runs for each `[demo.NAME]` of the config.
+use anyhow::Result;
+use async_trait::async_trait;
+use log::info;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::time::Duration;
+use yarrcfg::Demo;
+use yarrdata::provider::Provider;
+use yarrdata::status::{ProviderState, StatusHandle};
+use yarrdata::{
+    default_timestamp_ms, stop_channel, Author, Badge, Emote, Event, Message, MessageDeleted,
+    ProviderQueue, QueueStats, QueueSubscriber, Raid, Role, StopHandle, StopSignal, SubTier,
+    Subscription,
+};
+
+const ADJECTIVES: &[&str] = &[
+    "sleepy", "angry", "tiny", "cosmic", "salty", "happy", "lazy", "brave", "sneaky", "fluffy",
+    "rusty", "quantum", "spicy", "silent", "golden",
+];
+const NOUNS: &[&str] = &[
+    "otter", "pirate", "potato", "wizard", "panda", "crab", "ferris", "goblin", "noodle", "falcon",
+    "cactus", "badger", "kraken", "toaster", "llama",
+];
+/// Display names that aren't plain ASCII, with the login Twitch would give them.
+const UNICODE_USERS: &[(&str, &str)] = &[
+    ("zoe_b", "Zoë"),
+    ("yuki_chan", "ゆき"),
+    ("nandu", "Ñandú"),
+    ("ahmad99", "أحمد"),
+    ("bjork", "Björk_ÆØÅ"),
+    ("dragonboi", "🐉DragonBoi🐉"),
+    ("minjun", "민준"),
+];
+const COLORS: &[&str] = &[
+    "#FF0000", "#0000FF", "#008000", "#B22222", "#FF7F50", "#9ACD32", "#FF4500", "#2E8B57",
+    "#DAA520", "#D2691E", "#5F9EA0", "#1E90FF", "#FF69B4", "#8A2BE2", "#00FF7F", "",
+];
+const PHRASES: &[&str] = &[
+    "hello chat",
+    "first time here, love the stream",
+    "lol",
+    "that was close",
+    "gg",
+    "what keyboard is that?",
+    "can you explain that again? I missed the first part",
+    "the borrow checker strikes again",
+    "is this rust or c++?",
+    "!uptime",
+    "@{user} you're right",
+    "check out https://github.com/deavid/yarrosco",
+    "hola a todos, ¿qué tal?",
+    "Grüße aus Köln! Schönes Wetter heute 🌞",
+    "こんにちは！今日も配信ありがとう",
+    "Привет всем, отличный стрим",
+    "مرحبا بالجميع",
+    "안녕하세요 여러분",
+    "👀👀👀",
+    "🦀🦀🦀 rust gang 🦀🦀🦀",
+    "zalgo h̷e̸ ̵c̶o̴m̷e̴s̵",
+    "👨‍👩‍👧‍👦 family emoji and 🏳️‍🌈 flags take several code points each",
+    "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor \
+     incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud \
+     exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.",
+    "so I was thinking about this the whole day and I'm pretty sure the problem is that \
+     the overlay doesn't wrap very long words like Pneumonoultramicroscopicsilicovolcanoconiosis \
+     or URLs like https://example.com/a/very/long/path/that/never/ends/at/all?with=query&and=more",
+    "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+];
+/// Global Twitch emotes, by id and name.
+const EMOTES: &[(&str, &str)] = &[
+    ("25", "Kappa"),
+    ("41", "Kreygasm"),
+    ("86", "BibleThump"),
+    ("30259", "HeyGuys"),
+    ("64138", "SeemsGood"),
+    ("81274", "VoHiYo"),
+    ("425618", "LUL"),
+];
+
+/// A made-up user of the chat, with a stable look during the session.
+struct User {
+    author: Author,
+    badges: Vec<Badge>,
+}
+
+pub struct DemoClient {
+    cfg: Demo,
+    rng: StdRng,
+    users: Vec<User>,
+    /// Messages that can still be deleted, as (author, msgid).
+    recent: VecDeque<(String, String)>,
+    /// Start of the session, to make msgids that don't repeat between runs.
+    started: u64,
+    count: u64,
+    status: StatusHandle,
+    queue: ProviderQueue,
+    stop: StopSignal,
+    stop_handle: StopHandle,
+}
+
+impl DemoClient {
+    const USERS: usize = 40;
+    const RECENT: usize = 20;
+
+    fn next_msgid(&mut self) -> String {
+        self.count += 1;
+        format!("demo-{}-{}", self.started, self.count)
+    }
+    fn user(&mut self) -> &User {
+        // A few users write most of the messages, like in a real chat.
+        let n = self.users.len();
+        let idx = if self.rng.gen_bool(0.6) {
+            self.rng.gen_range(0..n / 4)
+        } else {
+            self.rng.gen_range(0..n)
+        };
+        &self.users[idx]
+    }
+    /// Some text with emotes in between, and the emotes at their positions.
+    pub fn text(&mut self) -> (String, Vec<Emote>) {
+        let mut words: Vec<String> = vec![];
+        if self.rng.gen_bool(0.8) {
+            let phrase = PHRASES.choose(&mut self.rng).unwrap();
+            let mention = &self.users.choose(&mut self.rng).unwrap().author.login;
+            words.push(phrase.replace("{user}", mention));
+        }
+        let emotes = match words.is_empty() {
+            true => self.rng.gen_range(1..=3),
+            false if self.rng.gen_bool(0.4) => self.rng.gen_range(1..=3),
+            false => 0,
+        };
+        for _ in 0..emotes {
+            let (_, name) = EMOTES.choose(&mut self.rng).unwrap();
+            let pos = self.rng.gen_range(0..=words.len());
+            words.insert(pos, name.to_string());
+        }
+        // Positions are in characters, with `to` included, as Twitch sends them.
+        let mut text = String::new();
+        let mut found = vec![];
+        for word in words {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            let from = text.chars().count();
+            if let Some((id, name)) = EMOTES.iter().find(|(_, name)| *name == word) {
+                found.push(Emote {
+                    id: id.to_string(),
+                    from,
+                    to: from + name.chars().count() - 1,
+                    name: name.to_string(),
+                    url: format!(
+                        "https://static-cdn.jtvnw.net/emoticons/v2/{}/static/light/2.0",
+                        id
+                    ),
+                });
+            }
+            text.push_str(&word);
+        }
+        (text, found)
+    }
+    fn message(&mut self) -> Event {
+        let msgid = self.next_msgid();
+        let (message, emotes) = self.text();
+        let user = self.user();
+        let (author, badges) = (user.author.clone(), user.badges.clone());
+        self.recent
+            .push_back((author.display_name.clone(), msgid.clone()));
+        if self.recent.len() > Self::RECENT {
+            self.recent.pop_front();
+        }
+        Event::Message(Message {
+            provider_name: self.cfg.provider_name.clone(),
+            room: self.cfg.room.clone(),
+            message,
+            username: author.display_name.clone(),
+            author,
+            msgid,
+            timestamp: default_timestamp_ms(),
+            badges,
+            emotes,
+            ..Message::default()
+        })
+    }
+    fn subscription(&mut self) -> Event {
+        let msgid = self.next_msgid();
+        let (message, emotes) = match self.rng.gen_bool(0.5) {
+            true => self.text(),
+            false => (String::new(), vec![]),
+        };
+        let months = self.rng.gen_range(1..=48);
+        let tier = *[
+            SubTier::Prime,
+            SubTier::Tier1,
+            SubTier::Tier2,
+            SubTier::Tier3,
+        ]
+        .choose(&mut self.rng)
+        .unwrap();
+        let plan = match tier {
+            SubTier::Prime => "with Prime Gaming",
+            SubTier::Tier1 => "at Tier 1",
+            SubTier::Tier2 => "at Tier 2",
+            SubTier::Tier3 => "at Tier 3",
+        };
+        let user = self.user();
+        let username = user.author.display_name.clone();
+        let badges = user.badges.clone();
+        Event::Subscription(Subscription {
+            provider_name: self.cfg.provider_name.clone(),
+            room: self.cfg.room.clone(),
+            system_message: format!(
+                "{} subscribed {}. They've subscribed for {} months!",
+                username, plan, months
+            ),
+            username,
+            msgid,
+            timestamp: default_timestamp_ms(),
+            months,
+            tier,
+            message,
+            badges,
+            emotes,
+            ..Subscription::default()
+        })
+    }
+    fn raid(&mut self) -> Event {
+        let msgid = self.next_msgid();
+        let username = format!(
+            "{}_{}",
+            ADJECTIVES.choose(&mut self.rng).unwrap(),
+            NOUNS.choose(&mut self.rng).unwrap()
+        );
+        let viewers = self.rng.gen_range(2..=500);
+        Event::Raid(Raid {
+            provider_name: self.cfg.provider_name.clone(),
+            room: self.cfg.room.clone(),
+            system_message: format!("{} raiders from {} have joined!", viewers, username),
+            username,
+            msgid,
+            timestamp: default_timestamp_ms(),
+            viewers,
+        })
+    }
+    /// Removes one of the last messages, or sends a message if there's none.
+    fn deletion(&mut self) -> Event {
+        if self.recent.is_empty() {
+            return self.message();
+        }
+        let idx = self.rng.gen_range(0..self.recent.len());
+        let (username, target_msgid) = self.recent.remove(idx).unwrap();
+        Event::MessageDeleted(MessageDeleted {
+            provider_name: self.cfg.provider_name.clone(),
+            room: self.cfg.room.clone(),
+            username,
+            msgid: format!("clearmsg-{}", target_msgid),
+            target_msgid,
+            timestamp: default_timestamp_ms(),
+        })
+    }
+    pub fn event(&mut self) -> Event {
+        match self.rng.gen_range(0..100) {
+            0..=3 => self.subscription(),
+            4..=5 => self.raid(),
+            6..=9 => self.deletion(),
+            _ => self.message(),
+        }
+    }
+    /// Time until the next event, around `1 / rate` seconds.
+    fn delay(&mut self) -> Duration {
+        Duration::from_secs_f64(self.rng.gen_range(0.2..1.8) / self.cfg.rate)
+    }
+}
+
+#[async_trait]
+impl Provider for DemoClient {
+    type Config = Demo;
+
+    async fn new(cfg: &Demo) -> Result<Self> {
+        let mut rng = match cfg.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let users = (0..Self::USERS).map(|n| make_user(&mut rng, n)).collect();
+        let (stop_handle, stop) = stop_channel();
+        Ok(Self {
+            cfg: cfg.clone(),
+            rng,
+            users,
+            recent: VecDeque::new(),
+            started: default_timestamp_ms(),
+            count: 0,
+            status: StatusHandle::new(),
+            queue: ProviderQueue::new(cfg.provider_name.clone()),
+            stop,
+            stop_handle,
+        })
+    }
+    fn subscribe(&self) -> QueueSubscriber {
+        self.queue.subscribe()
+    }
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+    fn set_status_handle(&mut self, status: StatusHandle) {
+        self.status = status;
+    }
+    fn set_queue_stats(&mut self, stats: QueueStats) {
+        self.queue.stats = stats;
+    }
+    async fn run(&mut self) -> Result<()> {
+        info!(
+            "demo chat in {:?}, {} events per second",
+            self.cfg.room, self.cfg.rate
+        );
+        self.status.set_state(ProviderState::Ready);
+        let mut stop = self.stop.clone();
+        let result = loop {
+            let delay = self.delay();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop.stopped() => break Ok(()),
+            }
+            let e = self.event();
+            self.status.activity();
+            if let Err(e) = self.queue.publish(e).await {
+                break Err(e);
+            }
+        };
+        match &result {
+            Ok(()) => {
+                self.status.set_state(ProviderState::Stopped);
+            }
+            Err(e) => self.status.fail(format!("{:#}", e)),
+        }
+        // Ends the subscriber streams, so consumers know no more events will come.
+        self.queue.close();
+        result
+    }
+}
+
+/// The first user is the broadcaster, the next ones moderators, and the rest
+/// have random roles.
+fn make_user(rng: &mut StdRng, n: usize) -> User {
+    let (login, display_name) = match rng.gen_bool(0.15) {
+        true => {
+            let (login, name) = UNICODE_USERS.choose(rng).unwrap();
+            (format!("{}{}", login, n), name.to_string())
+        }
+        false => {
+            let adjective = ADJECTIVES.choose(rng).unwrap();
+            let noun = NOUNS.choose(rng).unwrap();
+            let name = match rng.gen_range(0..3) {
+                0 => format!("{}_{}{}", adjective, noun, n),
+                1 => format!("{}{}{}", capitalize(adjective), capitalize(noun), n),
+                _ => format!("x{}{}x_{}", noun.to_uppercase(), adjective, n),
+            };
+            (name.to_lowercase(), name)
+        }
+    };
+    let mut roles = vec![];
+    match n {
+        0 => roles.push(Role::Broadcaster),
+        1..=3 => roles.push(Role::Moderator),
+        _ if rng.gen_bool(0.1) => roles.push(Role::Vip),
+        _ => {}
+    }
+    if n > 0 && rng.gen_bool(0.4) {
+        roles.push(Role::Subscriber);
+    }
+    let badges = roles.iter().map(|role| badge(*role, rng)).collect();
+    let author = Author {
+        id: (100000 + n).to_string(),
+        login,
+        display_name,
+        color: COLORS.choose(rng).unwrap().to_string(),
+        avatar_url: String::new(),
+        roles,
+    };
+    User { author, badges }
+}
+
+/// The badge Twitch shows for the role.
+fn badge(role: Role, rng: &mut StdRng) -> Badge {
+    let (name, vid, image) = match role {
+        Role::Broadcaster => (
+            "broadcaster",
+            "1".to_owned(),
+            "5527c58c-fb7d-422d-b71b-f309dcb85cc1",
+        ),
+        Role::Moderator => (
+            "moderator",
+            "1".to_owned(),
+            "3267646d-33f0-4b17-b3df-f923a41db1d0",
+        ),
+        Role::Vip => (
+            "vip",
+            "1".to_owned(),
+            "b817aba4-fad8-49e2-b88a-7cc744dfa6ec",
+        ),
+        Role::Subscriber => {
+            let months = *[0, 3, 6, 12, 24].choose(rng).unwrap();
+            (
+                "subscriber",
+                months.to_string(),
+                "5d9f2208-5dd8-11e7-8513-2ff4adfae661",
+            )
+        }
+    };
+    Badge {
+        name: name.to_owned(),
+        vid,
+        url: format!("https://static-cdn.jtvnw.net/badges/v1/{}/2", image),
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,110 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use yarrdata::status::{ProviderState, ProviderStatus, StatusHandle};
+use yarrdata::{default_timestamp, QueueStats};
+
+use crate::providers::ProviderKey;
+
+/// What a provider reports about itself. It's kept across restarts of the
+/// provider, and handed to each new client.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderHandles {
+    pub status: StatusHandle,
+    pub queue: QueueStats,
+}
+
+/// Status of every configured provider, for `/health`, `/metrics` and the log summary.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    providers: Arc<Mutex<BTreeMap<String, ProviderHandles>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// True when every provider is ready and receiving data.
+    pub healthy: bool,
+    pub providers: BTreeMap<String, ProviderHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderHealth {
+    #[serde(flatten)]
+    pub status: ProviderStatus,
+    /// Ready, but nothing was received for longer than `Health::MAX_IDLE`.
+    pub stale: bool,
+}
+
+impl Health {
+    /// Seconds without any activity after which a ready provider is suspicious.
+    /// Twitch pings every 5 minutes and Matrix syncs every 30 seconds.
+    pub const MAX_IDLE: u64 = 600;
+
+    pub fn insert(&self, key: &ProviderKey, handles: ProviderHandles) {
+        self.providers
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), handles);
+    }
+    pub fn remove(&self, key: &ProviderKey) {
+        self.providers.lock().unwrap().remove(&key.to_string());
+    }
+    pub fn report(&self) -> HealthReport {
+        let now = default_timestamp();
+        let providers: BTreeMap<String, ProviderHealth> = self
+            .providers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, handles)| {
+                let status = handles.status.get();
+                let stale = status.is_stale(now, Self::MAX_IDLE);
+                (name.clone(), ProviderHealth { status, stale })
+            })
+            .collect();
+        let healthy = providers
+            .values()
+            .all(|p| p.status.state == ProviderState::Ready && !p.stale);
+        HealthReport { healthy, providers }
+    }
+    /// The queue counters of each provider.
+    pub fn queues(&self) -> BTreeMap<String, QueueStats> {
+        self.providers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, handles)| (name.clone(), handles.queue.clone()))
+            .collect()
+    }
+    /// Logs one line with the state of every provider, as a warning if any isn't healthy.
+    pub fn log_summary(&self) {
+        let report = self.report();
+        if report.providers.is_empty() {
+            return;
+        }
+        let now = default_timestamp();
+        let summary: Vec<String> = report
+            .providers
+            .iter()
+            .map(|(name, p)| {
+                let mut line = format!("{} {}", name, p.status.state);
+                if p.stale {
+                    let last = p.status.last_activity.unwrap_or(p.status.since);
+                    line += &format!(" but idle for {}s", now.saturating_sub(last));
+                }
+                if p.status.state != ProviderState::Ready {
+                    if let Some(e) = &p.status.last_error {
+                        line += &format!(" (last error: {})", e);
+                    }
+                }
+                line
+            })
+            .collect();
+        if report.healthy {
+            info!("providers: {}", summary.join(", "));
+        } else {
+            warn!("providers: {}", summary.join(", "));
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::{Context, Result};
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use warp::http::{StatusCode, Uri};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Reply};
+use yarrdata::db::{self, EventId};
+use yarrdata::router::{self, Router};
+use yarrdata::{Event, QueueSubscriber, Received, StopSignal};
+
+use crate::health::Health;
+use crate::metrics::Metrics;
+
+/// Serves the HTML overlay from `assets_dir` and the database straight from
+/// memory, so no external web server or symlinks are needed. Uses the
+/// `[http]` section and the filters of `cfg`.
+pub async fn serve(
+    cfg: Arc<yarrcfg::Config>,
+    log: Arc<Mutex<db::Log>>,
+    router: Router,
+    health: Health,
+    metrics: Metrics,
+    mut stop: StopSignal,
+) -> Result<()> {
+    let http = &cfg.http;
+    let addr = tokio::net::lookup_host((http.bind.as_str(), http.port))
+        .await
+        .with_context(|| format!("couldn't resolve http bind address {:?}", http.bind))?
+        .next()
+        .with_context(|| format!("no address found for {:?}", http.bind))?;
+
+    let with_log = warp::any().map(move || Arc::clone(&log));
+    let with_router = warp::any().map(move || router.clone());
+    let assets_dir = http.assets_dir.clone();
+    let with_cfg = warp::any().map(move || Arc::clone(&cfg));
+    let with_health = warp::any().map(move || health.clone());
+    let with_metrics = warp::any().map(move || metrics.clone());
+    // The overlay polls both files. Since the whole database is in memory we
+    // serve the same snapshot for both; the overlay discards duplicates.
+    let db_files = warp::get()
+        .and(
+            warp::path("yarrdb_data.jsonl")
+                .or(warp::path("yarrdb_log.jsonl"))
+                .unify(),
+        )
+        .and(warp::path::end())
+        .and(warp::query::<SnapshotQuery>())
+        .and(with_log.clone())
+        .and(with_cfg.clone())
+        .and_then(db_snapshot);
+    let api_events = warp::get()
+        .and(warp::path!("api" / "events"))
+        .and(warp::query::<db::Query>())
+        .and(with_log.clone())
+        .and_then(query_events);
+    let ws_feed = warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::query::<FeedQuery>())
+        .and(with_log.clone())
+        .and(with_router.clone())
+        .and(with_cfg)
+        .and_then(
+            |ws: Ws, query: FeedQuery, log, router, cfg: Arc<yarrcfg::Config>| async move {
+                let filter = match cfg.filter(query.filter.as_deref()) {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        let reply =
+                            warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST);
+                        return Ok::<_, Infallible>(reply.into_response());
+                    }
+                };
+                let upgrade =
+                    ws.on_upgrade(move |socket| feed_client(socket, query, filter, log, router));
+                Ok(upgrade.into_response())
+            },
+        );
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_log)
+        .and(with_health.clone())
+        .and(with_router)
+        .and(with_metrics)
+        .and_then(render_metrics);
+    let health = warp::get()
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and(with_health)
+        .map(health_report);
+    let index = warp::get()
+        .and(warp::path::end())
+        .map(|| warp::redirect::temporary(Uri::from_static("/yarrosco_chat.html")));
+    let assets = warp::get().and(warp::fs::dir(assets_dir.clone()));
+    let routes = db_files
+        .or(api_events)
+        .or(ws_feed)
+        .or(health)
+        .or(metrics)
+        .or(index)
+        .or(assets);
+
+    let (addr, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(addr, async move { stop.stopped().await })
+        .with_context(|| format!("couldn't listen on {}", addr))?;
+    info!("serving overlay from {:?} on http://{}/", assets_dir, addr);
+    server.await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    /// Name of one of the `[filters]` of the config.
+    filter: Option<String>,
+}
+
+async fn db_snapshot(
+    query: SnapshotQuery,
+    log: Arc<Mutex<db::Log>>,
+    cfg: Arc<yarrcfg::Config>,
+) -> Result<warp::reply::Response, Infallible> {
+    let filter = match cfg.filter(query.filter.as_deref()) {
+        Ok(filter) => filter,
+        Err(e) => {
+            let reply = warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
+    let body = match query.filter {
+        None => log.lock().await.to_jsonl(),
+        Some(_) => log
+            .lock()
+            .await
+            .events_after(None)
+            .filter(|(_, ce)| filter.matches(None, &ce.event))
+            .map(|(_, ce)| ce.json.as_str())
+            .collect(),
+    };
+    let reply = warp::reply::with_header(body, CONTENT_TYPE, "application/x-ndjson; charset=utf-8");
+    // Browsers and OBS tend to cache these aggressively, and the content changes constantly.
+    Ok(warp::reply::with_header(reply, CACHE_CONTROL, "no-store").into_response())
+}
+
+/// 200 if every provider is ready, 503 otherwise; the body has the details.
+fn health_report(health: Health) -> impl Reply {
+    let report = health.report();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let reply = warp::reply::with_status(warp::reply::json(&report), status);
+    warp::reply::with_header(reply, CACHE_CONTROL, "no-store")
+}
+
+async fn render_metrics(
+    log: Arc<Mutex<db::Log>>,
+    health: Health,
+    router: Router,
+    metrics: Metrics,
+) -> Result<warp::reply::Response, Infallible> {
+    match metrics.render(&log, &health, &router).await {
+        Ok(text) => {
+            let reply = warp::reply::with_header(text, CONTENT_TYPE, prometheus::TEXT_FORMAT);
+            Ok(reply.into_response())
+        }
+        Err(e) => {
+            let msg = format!("couldn't render metrics: {:?}", e);
+            Ok(warp::reply::with_status(msg, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventsPage {
+    events: Vec<FeedEntry>,
+    next_cursor: Option<String>,
+    has_more: bool,
+}
+
+/// History API: `/api/events?cursor=&from_ts=&to_ts=&provider=&room=&username=&limit=`
+async fn query_events(
+    query: db::Query,
+    log: Arc<Mutex<db::Log>>,
+) -> Result<warp::reply::Response, Infallible> {
+    let page = log.lock().await.query(&query);
+    let page = match page {
+        Ok(page) => page,
+        Err(e) => {
+            let msg = format!("invalid query: {}", e);
+            return Ok(warp::reply::with_status(msg, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+    let body = EventsPage {
+        events: page
+            .events
+            .into_iter()
+            .map(|(id, event)| FeedEntry {
+                cursor: id.to_cursor(),
+                event,
+            })
+            .collect(),
+        next_cursor: page.next_cursor.map(|id| id.to_cursor()),
+        has_more: page.has_more,
+    };
+    let reply = warp::reply::json(&body);
+    Ok(warp::reply::with_header(reply, CACHE_CONTROL, "no-store").into_response())
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    /// Cursor of the last event the client saw, to resume after a reconnect.
+    since: Option<String>,
+    /// Name of one of the `[filters]` of the config.
+    filter: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FeedEntry {
+    cursor: String,
+    event: Event,
+}
+
+impl FeedEntry {
+    fn new(id: &EventId, event: &Event) -> Self {
+        Self {
+            cursor: id.to_cursor(),
+            event: event.clone(),
+        }
+    }
+}
+
+/// Frames sent through the websocket, as JSON text messages.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    Snapshot { events: Vec<FeedEntry> },
+    Event(Box<FeedEntry>),
+}
+
+impl Frame {
+    fn to_message(&self) -> Result<Message> {
+        Ok(Message::text(serde_json::to_string(self)?))
+    }
+}
+
+async fn feed_client(
+    socket: WebSocket,
+    query: FeedQuery,
+    filter: router::Filter,
+    log: Arc<Mutex<db::Log>>,
+    router: Router,
+) {
+    let mut last = match query.since.as_deref().map(EventId::from_cursor) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(e)) => {
+            warn!("websocket client sent an invalid cursor: {:?}", e);
+            None
+        }
+        None => None,
+    };
+    let (mut tx, mut rx) = socket.split();
+    let mut events = match send_snapshot(&mut tx, &log, &router, &filter, &mut last).await {
+        Ok(events) => events,
+        Err(e) => {
+            debug!("websocket client gone while sending snapshot: {:?}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            msg = rx.next() => match msg {
+                // We don't expect anything from the client, pings are answered by warp.
+                Some(Ok(msg)) if !msg.is_close() => {}
+                _ => break,
+            },
+            ev = events.recv() => {
+                let sent = match ev {
+                    Received::Event(ev) => {
+                        let id = EventId::from_event(&ev);
+                        let sent = send_frame(&mut tx, Frame::Event(Box::new(FeedEntry::new(&id, &ev)))).await;
+                        last = Some(id);
+                        sent
+                    }
+                    Received::Lagged(n) => {
+                        warn!("websocket client lagged behind by {} events, resyncing", n);
+                        match send_snapshot(&mut tx, &log, &router, &filter, &mut last).await {
+                            Ok(new_events) => {
+                                events = new_events;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Received::Closed => break,
+                };
+                if let Err(e) = sent {
+                    debug!("websocket client gone: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends every stored event after `last` and returns a subscription to the
+/// events that come afterwards. Both are taken under the database lock, which
+/// is also held while publishing, so nothing is lost or sent twice.
+async fn send_snapshot(
+    tx: &mut SplitSink<WebSocket, Message>,
+    log: &Mutex<db::Log>,
+    router: &Router,
+    filter: &router::Filter,
+    last: &mut Option<EventId>,
+) -> Result<QueueSubscriber> {
+    let (entries, events) = {
+        let log = log.lock().await;
+        let mut entries = vec![];
+        for (id, ce) in log.events_after(last.as_ref()) {
+            if filter.matches(None, &ce.event) {
+                entries.push(FeedEntry::new(id, &ce.event));
+            }
+            *last = Some(id.clone());
+        }
+        (entries, router.subscribe("websocket", filter.clone()))
+    };
+    send_frame(tx, Frame::Snapshot { events: entries }).await?;
+    Ok(events)
+}
+
+async fn send_frame(tx: &mut SplitSink<WebSocket, Message>, frame: Frame) -> Result<()> {
+    tx.send(frame.to_message()?).await?;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use log::{error, info};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use yarrdata::db::{self, MessageIgnored};
+use yarrdata::router::Router;
+use yarrdata::Event;
+
+use crate::metrics::Metrics;
+use crate::providers::ProviderKey;
+
+/// Where the events from every provider end up: the database, and from there
+/// the websocket clients and the output sinks.
+pub struct Ingest {
+    pub log: Arc<Mutex<db::Log>>,
+    /// Every event accepted into the database is published here for the
+    /// websocket clients and the output sinks.
+    pub router: Router,
+    pub metrics: Metrics,
+}
+
+impl Ingest {
+    pub async fn process(&self, source: &ProviderKey, ev: Arc<Event>) {
+        let ev = Arc::try_unwrap(ev).unwrap_or_else(|ev| ev.as_ref().clone());
+        let mut logger_lck = self.log.lock().await;
+        let result = logger_lck.push(ev).await;
+        if let Ok((MessageIgnored::None, stored)) = &result {
+            // The event as stored, so the database, the websocket clients and
+            // the sinks all get the same. Published while holding the lock so a
+            // client taking a snapshot of the database can't miss it or
+            // receive it twice.
+            self.router.publish(Some(&source.to_string()), stored);
+        }
+        drop(logger_lck);
+        if let Ok((reason, ev)) = &result {
+            self.metrics.event_pushed(ev, reason);
+        }
+        match result {
+            Ok((MessageIgnored::None, _)) => {}
+            Ok((reason, ev)) => info!("ignored message {:?}: {:?}", reason, ev),
+            Err(e) => error!("trying to write message to log: {:?}", e),
+        }
+    }
+}
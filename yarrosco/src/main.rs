@@ -1,90 +1,146 @@
-use anyhow::Result;
-use futures::StreamExt;
+mod demo;
+mod health;
+mod http;
+mod ingest;
+mod metrics;
+mod providers;
+mod reload;
+mod replay;
+mod shutdown;
+mod sink;
+mod supervisor;
+mod tests;
+use anyhow::{Context, Result};
+use clap::Parser;
 use log::LevelFilter;
 use log::{error, info};
-use std::{borrow::Borrow, sync::Arc};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::task;
-use yarrdata::db::{self, MessageIgnored};
-use yarrdata::Event;
-use yarrmatrix::MatrixClient;
-use yarrtwitch::TwitchClient;
+use tokio::time::{self, Instant};
+use yarrcfg::{set_log_level, ConfigArgs, ConfigLoader};
+use yarrdata::db;
+use yarrdata::router::Router;
+use yarrdata::stop_channel;
+
+use crate::health::Health;
+use crate::ingest::Ingest;
+use crate::metrics::Metrics;
+use crate::providers::{Providers, Registry};
+use crate::reload::ConfigWatcher;
+use crate::sink::Sinks;
+
+/// How often the state of the providers is logged.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Collects the chats from Twitch and Matrix into a single feed for the overlay.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    cfg: ConfigArgs,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    set_log_level(args.cfg.log_level.as_deref());
     env_logger::builder()
         .filter(Some("sled"), LevelFilter::Info)
         .filter(Some("reqwest"), LevelFilter::Info)
         .init();
 
-    let cfg = yarrcfg::parse_config()?;
-    let mut subs = vec![];
-    let mut service_fut = vec![];
-    // Create yarrtwitch
-    for (_name, twitch_cfg) in cfg.twitch.iter() {
-        let mut tw = TwitchClient::new(twitch_cfg).await?;
-        // Subscribe to twitch
-        subs.push(tw.subscribe());
-        service_fut.push(task::spawn(async move { tw.run().await }));
-    }
-
-    // Create and connect to matrix
-    for (_name, matrix_cfg) in cfg.matrix.iter() {
-        let mut mx = MatrixClient::new(matrix_cfg)?;
-        // Subscribe to matrix
-        subs.push(mx.subscribe());
-        service_fut.push(task::spawn(async move { mx.run().await }));
-    }
+    let mut loader = ConfigLoader::from_args(&args.cfg);
+    let mut cfg = loader.load()?;
 
     // Read from database
-    let mut log = db::Log::new(100, cfg.logfile, cfg.checkpointfile);
+    let mut log = db::Log::new(100, cfg.logfile.clone(), cfg.checkpointfile.clone());
     if let Err(e) = log.load().await {
         error!("couldn't load the database: {:?}", e);
     }
     if let Err(e) = log.perform_checkpoint().await {
         error!("couldn't checkpoint the database: {:?}", e);
     }
-    for (_, ce) in log.data.iter() {
-        process_message(&ce.event);
-    }
+    let router = Router::new();
     let log: Arc<Mutex<db::Log>> = Arc::new(Mutex::new(log));
+    let sinks = Sinks::start(&cfg, &router, &log).await?;
+    let health = Health::default();
+    let metrics = Metrics::new()?;
+    let (http_stop_handle, http_stop) = stop_channel();
+    let http_server = if cfg.http.enabled {
+        let http_cfg = Arc::new(cfg.clone());
+        let http_log = Arc::clone(&log);
+        let http_router = router.clone();
+        let http_health = health.clone();
+        let http_metrics = metrics.clone();
+        Some(task::spawn(async move {
+            let served = http::serve(
+                http_cfg,
+                http_log,
+                http_router,
+                http_health,
+                http_metrics,
+                http_stop,
+            );
+            if let Err(e) = served.await {
+                error!("http server stopped: {:?}", e);
+            }
+        }))
+    } else {
+        None
+    };
     // TODO: Implement a yarrosco-secondary to have as a background + backup (name: yarrly? yarrdy? female-parrot)
-    // Upon receiving a new matrix message...
-    let futures_sub = subs.into_iter().map(|sub| {
-        sub.for_each_concurrent(2, |event| async {
-            process_message_log(Arc::clone(&log), event).await;
-        })
+    let ingest = Arc::new(Ingest {
+        log: Arc::clone(&log),
+        router: router.clone(),
+        metrics,
     });
-    use futures::stream::FuturesUnordered;
-    let mut futures = FuturesUnordered::new();
-    futures.extend(futures_sub);
-    while futures.next().await.is_some() {}
-
-    for fut in service_fut {
-        fut.await??;
-    }
-    Ok(())
-}
+    let mut providers = Providers::new(Registry::builtin(), Arc::clone(&ingest), health.clone());
+    providers.apply(&cfg).await;
 
-async fn process_message_log(logger: Arc<Mutex<db::Log>>, ev: Arc<Event>) {
-    let event: &Event = ev.borrow();
-    let mut logger_lck = logger.lock().await;
-    let result = logger_lck.push(event.clone()).await;
-    drop(logger_lck);
-    match result {
-        Ok(MessageIgnored::None) => process_message(event),
-        Ok(reason) => info!("ignored message {:?}: {:?}", reason, event),
-        Err(e) => error!("trying to write message to log: {:?}", e),
+    let mut watcher = ConfigWatcher::new(loader);
+    let signal = shutdown::wait_for_signal();
+    tokio::pin!(signal);
+    let mut summary = time::interval_at(Instant::now() + SUMMARY_INTERVAL, SUMMARY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = summary.tick() => health.log_summary(),
+            sig = &mut signal => {
+                match sig {
+                    Ok(name) => info!("{} received, shutting down (send it again to force exit)", name),
+                    Err(e) => error!("couldn't listen for signals, shutting down: {:?}", e),
+                }
+                break;
+            }
+            new_cfg = watcher.next_config() => {
+                reload::warn_unapplied(&cfg, &new_cfg);
+                providers.apply(&new_cfg).await;
+                info!("config reloaded");
+                cfg = new_cfg;
+            }
+        }
     }
-}
 
-fn process_message(event: &Event) {
-    match event {
-        Event::Message(m) => {
-            println!("#{}::{}> {}", m.provider_name, m.username, m.message)
+    let failed = providers.stop_all().await;
+    drop(providers);
+    // Ends the subscriptions of the sinks and the websocket clients.
+    router.close();
+    http_stop_handle.stop();
+    if let Some(http_server) = http_server {
+        if let Err(e) = http_server.await {
+            error!("http server task failed: {:?}", e);
         }
     }
+    log.lock()
+        .await
+        .perform_checkpoint()
+        .await
+        .context("final checkpoint of the database failed")?;
+    sinks.close().await;
+    info!(
+        "shutdown complete, database saved ({} providers ended with errors)",
+        failed
+    );
+    Ok(())
 }
@@ -0,0 +1,173 @@
+use anyhow::Result;
+use prometheus::{
+    Counter, Encoder, Gauge, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use yarrdata::db::{self, MessageIgnored};
+use yarrdata::router::Router;
+use yarrdata::status::ProviderState;
+use yarrdata::Event;
+
+use crate::health::Health;
+
+/// Prometheus metrics of the daemon, served on `/metrics`. Cheap to clone.
+///
+/// Event counters are updated as events arrive; everything else is sampled
+/// from the database and the providers when scraped.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Held while sampling and gathering, so concurrent scrapes don't add the
+    /// same increase twice or see the providers halfway through a refresh.
+    scrape: Arc<Mutex<()>>,
+    events: IntCounterVec,
+    ignored: IntCounterVec,
+    provider_ready: IntGaugeVec,
+    provider_reconnects: IntCounterVec,
+    queue_depth: IntGaugeVec,
+    subscriber_missed: IntCounter,
+    checkpoints: IntCounter,
+    checkpoint_seconds: Counter,
+    last_checkpoint_seconds: Gauge,
+    db_events: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("yarrosco".to_owned()), None)?;
+        let events = IntCounterVec::new(
+            Opts::new("events_total", "Events accepted into the database"),
+            &["provider", "room"],
+        )?;
+        let ignored = IntCounterVec::new(
+            Opts::new("events_ignored_total", "Events not stored, by reason"),
+            &["provider", "reason"],
+        )?;
+        let provider_ready = IntGaugeVec::new(
+            Opts::new("provider_ready", "1 if the provider is connected and ready"),
+            &["provider"],
+        )?;
+        let provider_reconnects = IntCounterVec::new(
+            Opts::new(
+                "provider_reconnects_total",
+                "Reconnections since the provider was started or reconfigured",
+            ),
+            &["provider"],
+        )?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "provider_queue_depth",
+                "Events waiting in the provider queue",
+            ),
+            &["provider"],
+        )?;
+        let subscriber_missed = IntCounter::new(
+            "subscriber_missed_total",
+            "Events skipped by websocket clients and sinks that fell behind",
+        )?;
+        let checkpoints = IntCounter::new("checkpoints_total", "Database checkpoints performed")?;
+        let checkpoint_seconds = Counter::new(
+            "checkpoint_seconds_total",
+            "Time spent performing database checkpoints",
+        )?;
+        let last_checkpoint_seconds = Gauge::new(
+            "last_checkpoint_seconds",
+            "Duration of the last database checkpoint",
+        )?;
+        let db_events = IntGauge::new("db_events", "Events held in the database")?;
+
+        registry.register(Box::new(events.clone()))?;
+        registry.register(Box::new(ignored.clone()))?;
+        registry.register(Box::new(provider_ready.clone()))?;
+        registry.register(Box::new(provider_reconnects.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(subscriber_missed.clone()))?;
+        registry.register(Box::new(checkpoints.clone()))?;
+        registry.register(Box::new(checkpoint_seconds.clone()))?;
+        registry.register(Box::new(last_checkpoint_seconds.clone()))?;
+        registry.register(Box::new(db_events.clone()))?;
+        Ok(Self {
+            registry,
+            scrape: Arc::new(Mutex::new(())),
+            events,
+            ignored,
+            provider_ready,
+            provider_reconnects,
+            queue_depth,
+            subscriber_missed,
+            checkpoints,
+            checkpoint_seconds,
+            last_checkpoint_seconds,
+            db_events,
+        })
+    }
+    pub fn event_pushed(&self, event: &Event, result: &MessageIgnored) {
+        let provider = event.provider_name();
+        match result {
+            MessageIgnored::None => self
+                .events
+                .with_label_values(&[provider, event.room()])
+                .inc(),
+            MessageIgnored::TooOld => self.ignored.with_label_values(&[provider, "too_old"]).inc(),
+            MessageIgnored::Duplicated => self
+                .ignored
+                .with_label_values(&[provider, "duplicated"])
+                .inc(),
+            MessageIgnored::Deleted => self.ignored.with_label_values(&[provider, "deleted"]).inc(),
+        }
+    }
+    /// Samples the current state and returns all metrics in the text format.
+    pub async fn render(
+        &self,
+        log: &Mutex<db::Log>,
+        health: &Health,
+        router: &Router,
+    ) -> Result<String> {
+        let _scrape = self.scrape.lock().await;
+        let (stats, db_events) = {
+            let log = log.lock().await;
+            (log.checkpoint_stats(), log.data.len())
+        };
+        // Only this function updates these, one scrape at a time, so catching
+        // up keeps them monotonic.
+        self.checkpoints
+            .inc_by(stats.count.saturating_sub(self.checkpoints.get()));
+        let total = stats.total_duration.as_secs_f64();
+        if total > self.checkpoint_seconds.get() {
+            self.checkpoint_seconds
+                .inc_by(total - self.checkpoint_seconds.get());
+        }
+        self.last_checkpoint_seconds
+            .set(stats.last_duration.as_secs_f64());
+        self.db_events.set(db_events as i64);
+        self.subscriber_missed
+            .inc_by(router.missed().saturating_sub(self.subscriber_missed.get()));
+
+        // Reset so providers removed from the config disappear.
+        self.provider_ready.reset();
+        self.provider_reconnects.reset();
+        self.queue_depth.reset();
+        for (name, p) in health.report().providers.iter() {
+            let ready = p.status.state == ProviderState::Ready && !p.stale;
+            self.provider_ready
+                .with_label_values(&[name])
+                .set(ready as i64);
+            // Copies the count of the provider. It starts again from 0 when the
+            // provider is reconfigured, which Prometheus takes as a counter reset.
+            self.provider_reconnects
+                .with_label_values(&[name])
+                .inc_by(p.status.reconnects as u64);
+        }
+        for (name, queue) in health.queues() {
+            self.queue_depth
+                .with_label_values(&[&name])
+                .set(queue.depth() as i64);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::task::{self, JoinHandle};
+use yarrcfg::Config;
+use yarrdata::provider::Provider;
+use yarrdata::{stop_channel, StopHandle};
+use yarrmatrix::MatrixClient;
+use yarrtwitch::TwitchClient;
+
+use crate::demo::DemoClient;
+use crate::health::{Health, ProviderHandles};
+use crate::ingest::Ingest;
+use crate::replay::ReplayClient;
+use crate::supervisor::{supervise, StartFn, Started};
+
+/// Identifies a provider by its config section, e.g. `[twitch.servername]`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProviderKey {
+    pub kind: &'static str,
+    pub name: String,
+}
+
+impl ProviderKey {
+    pub fn new(kind: &'static str, name: &str) -> Self {
+        Self {
+            kind,
+            name: name.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ProviderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.kind, self.name)
+    }
+}
+
+/// The kinds of providers the daemon can run, keyed by their config section
+/// type: each `[twitch.NAME]` runs a client of the kind registered as "twitch".
+pub struct Registry {
+    kinds: Vec<Box<dyn ProviderKind>>,
+}
+
+impl Registry {
+    /// Every chat network yarrosco knows about.
+    pub fn builtin() -> Self {
+        let mut registry = Self { kinds: vec![] };
+        registry.register::<TwitchClient>("twitch", |cfg| &cfg.twitch);
+        registry.register::<MatrixClient>("matrix", |cfg| &cfg.matrix);
+        registry.register::<DemoClient>("demo", |cfg| &cfg.demo);
+        registry.register::<ReplayClient>("replay", |cfg| &cfg.replay);
+        registry
+    }
+    /// Runs a `P` for each section returned by `sections`, named `[kind.NAME]`.
+    pub fn register<P: Provider>(
+        &mut self,
+        kind: &'static str,
+        sections: fn(&Config) -> &BTreeMap<String, P::Config>,
+    ) {
+        self.kinds.push(Box::new(Kind::<P> { kind, sections }));
+    }
+}
+
+/// A registered kind of provider, without the type of its config section.
+trait ProviderKind: Send + Sync {
+    fn kind(&self) -> &'static str;
+    /// Returns the sections that were removed, and the ones that were added or changed.
+    fn diff(&self, old: Option<&Config>, new: &Config) -> (Vec<String>, Vec<String>);
+    /// Creates the clients for the section with this name.
+    fn start_fn(&self, cfg: &Config, name: &str) -> StartFn;
+}
+
+struct Kind<P: Provider> {
+    kind: &'static str,
+    sections: fn(&Config) -> &BTreeMap<String, P::Config>,
+}
+
+impl<P: Provider> ProviderKind for Kind<P> {
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+    fn diff(&self, old: Option<&Config>, new: &Config) -> (Vec<String>, Vec<String>) {
+        let none = BTreeMap::new();
+        let old = old.map(self.sections).unwrap_or(&none);
+        diff_sections(old, (self.sections)(new))
+    }
+    fn start_fn(&self, cfg: &Config, name: &str) -> StartFn {
+        let section = (self.sections)(cfg)[name].clone();
+        Box::new(move |handles: ProviderHandles| {
+            let section = section.clone();
+            Box::pin(async move {
+                let mut client = P::new(&section).await?;
+                client.set_status_handle(handles.status);
+                client.set_queue_stats(handles.queue);
+                let sub = client.subscribe();
+                let stop = client.stop_handle();
+                let run = task::spawn(async move { client.run().await });
+                Ok(Started { stop, sub, run })
+            })
+        })
+    }
+}
+
+struct RunningProvider {
+    stop: StopHandle,
+    /// The supervisor, ends once the provider stopped and all its events were processed.
+    task: JoinHandle<Result<()>>,
+}
+
+/// The providers currently running, kept in sync with the config.
+pub struct Providers {
+    registry: Arc<Registry>,
+    ingest: Arc<Ingest>,
+    health: Health,
+    /// The config the running providers were started from.
+    applied: Option<Config>,
+    running: BTreeMap<ProviderKey, RunningProvider>,
+}
+
+impl Providers {
+    pub fn new(registry: Registry, ingest: Arc<Ingest>, health: Health) -> Self {
+        Self {
+            registry: Arc::new(registry),
+            ingest,
+            health,
+            applied: None,
+            running: BTreeMap::new(),
+        }
+    }
+    /// Starts, stops or restarts providers so they match the config. Providers
+    /// whose section didn't change are left alone.
+    pub async fn apply(&mut self, cfg: &Config) {
+        let registry = Arc::clone(&self.registry);
+        for kind in registry.kinds.iter() {
+            let (removed, changed) = kind.diff(self.applied.as_ref(), cfg);
+            for name in removed.iter().chain(changed.iter()) {
+                self.stop(&ProviderKey::new(kind.kind(), name)).await;
+            }
+            for name in changed.iter() {
+                self.spawn(
+                    ProviderKey::new(kind.kind(), name),
+                    kind.start_fn(cfg, name),
+                );
+            }
+        }
+        self.applied = Some(cfg.clone());
+    }
+    fn spawn(&mut self, key: ProviderKey, start: StartFn) {
+        let (stop, stop_signal) = stop_channel();
+        let ingest = Arc::clone(&self.ingest);
+        let handles = ProviderHandles::default();
+        self.health.insert(&key, handles.clone());
+        let task = task::spawn(supervise(key.clone(), ingest, stop_signal, handles, start));
+        self.running.insert(key, RunningProvider { stop, task });
+    }
+    async fn stop(&mut self, key: &ProviderKey) {
+        if let Some(provider) = self.running.remove(key) {
+            info!("stopping provider {}", key);
+            provider.stop.stop();
+            log_result(key, provider.task.await);
+            self.health.remove(key);
+        }
+    }
+    /// Stops every provider and waits for them. Returns how many failed.
+    pub async fn stop_all(&mut self) -> usize {
+        let running = std::mem::take(&mut self.running);
+        for provider in running.values() {
+            provider.stop.stop();
+        }
+        let mut failed = 0;
+        for (key, provider) in running {
+            if !log_result(&key, provider.task.await) {
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+fn log_result(key: &ProviderKey, result: Result<Result<()>, task::JoinError>) -> bool {
+    match result {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("provider {} ended with an error: {:?}", key, e);
+            false
+        }
+        Err(e) => {
+            error!("provider {} task failed: {:?}", key, e);
+            false
+        }
+    }
+}
+
+/// Returns the sections that were removed, and the ones that were added or changed.
+fn diff_sections<T: PartialEq>(
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+) -> (Vec<String>, Vec<String>) {
+    let removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .cloned()
+        .collect();
+    let changed = new
+        .iter()
+        .filter(|(name, cfg)| old.get(*name) != Some(cfg))
+        .map(|(name, _)| name.clone())
+        .collect();
+    (removed, changed)
+}
@@ -0,0 +1,105 @@
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use yarrcfg::{Config, ConfigLoader};
+
+/// Watches the config files and loads them again when they change.
+pub struct ConfigWatcher {
+    loader: Arc<Mutex<ConfigLoader>>,
+    paths: Vec<PathBuf>,
+    /// Times of the files when the current config was loaded.
+    mtimes: Vec<Option<SystemTime>>,
+    /// Times of the files when loading them last failed, and whether that
+    /// was already the retry.
+    failed: Option<(Vec<Option<SystemTime>>, bool)>,
+    interval: Interval,
+}
+
+impl ConfigWatcher {
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// The loader should be the one that loaded the current config, so it
+    /// doesn't ask for the passphrase again.
+    pub fn new(loader: ConfigLoader) -> Self {
+        let paths = loader.paths();
+        let mtimes = modified_times(&paths);
+        let mut interval = time::interval(Self::POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            loader: Arc::new(Mutex::new(loader)),
+            paths,
+            mtimes,
+            failed: None,
+            interval,
+        }
+    }
+    /// Waits until the config files change and returns the new config. If it
+    /// can't be loaded it's tried once more, in case the files were still
+    /// being written, and then the error is logged and it keeps waiting.
+    pub async fn next_config(&mut self) -> Config {
+        loop {
+            self.interval.tick().await;
+            let mtimes = modified_times(&self.paths);
+            if mtimes == self.mtimes {
+                continue;
+            }
+            let retry = match &self.failed {
+                Some((failed, true)) if *failed == mtimes => continue,
+                Some((failed, false)) => *failed == mtimes,
+                _ => false,
+            };
+            if !retry {
+                info!("config files changed, reloading");
+            }
+            let loader = Arc::clone(&self.loader);
+            // Decoding secrets is slow on purpose, keep it out of the runtime.
+            let result = task::spawn_blocking(move || loader.lock().unwrap().load()).await;
+            match result {
+                Ok(Ok(cfg)) => {
+                    self.mtimes = mtimes;
+                    self.failed = None;
+                    return cfg;
+                }
+                Ok(Err(e)) if !retry => {
+                    warn!("couldn't load the new config, trying again: {:#}", e);
+                    self.failed = Some((mtimes, false));
+                }
+                Ok(Err(e)) => {
+                    error!("new config rejected, keeping the current one: {:?}", e);
+                    self.failed = Some((mtimes, true));
+                }
+                Err(e) => {
+                    error!("config reload task failed: {:?}", e);
+                    self.failed = Some((mtimes, true));
+                }
+            }
+        }
+    }
+}
+
+/// Only providers are reloaded; warn about any other change so it isn't
+/// silently ignored.
+pub fn warn_unapplied(old: &Config, new: &Config) {
+    if old.logfile != new.logfile || old.checkpointfile != new.checkpointfile {
+        warn!("database file changes will apply after a restart");
+    }
+    if old.http != new.http {
+        warn!("[http] changes will apply after a restart");
+    }
+    if old.sinks != new.sinks {
+        warn!("[sinks] changes will apply after a restart");
+    }
+    if old.filters != new.filters {
+        warn!("[filters] changes will apply after a restart");
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
@@ -0,0 +1,144 @@
+//! A provider that publishes a recorded session again, to rehearse the overlay
+//! or debug the renderers with real data. Runs for each `[replay.NAME]`.
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use yarrcfg::Replay;
+use yarrdata::provider::Provider;
+use yarrdata::status::{ProviderState, StatusHandle};
+use yarrdata::{
+    default_timestamp_ms, stop_channel, Event, ProviderQueue, QueueStats, QueueSubscriber,
+    StopHandle, StopSignal,
+};
+
+pub struct ReplayClient {
+    cfg: Replay,
+    /// The recorded events, oldest first.
+    events: Vec<Event>,
+    status: StatusHandle,
+    queue: ProviderQueue,
+    stop: StopSignal,
+    stop_handle: StopHandle,
+}
+
+impl ReplayClient {
+    /// When the event recorded at `timestamp` is due, counting from `start`.
+    /// Fails if that's too far away to wait for, e.g. a bogus timestamp.
+    fn due(&self, start: Instant, first: u64, timestamp: u64) -> Result<Instant> {
+        let gap = timestamp.saturating_sub(first) as f64 / 1000.0;
+        Duration::try_from_secs_f64(gap / self.cfg.speed)
+            .ok()
+            .and_then(|wait| start.checked_add(wait))
+            .with_context(|| format!("event at {} is too far in the future", timestamp))
+    }
+    /// Publishes every event at its time. Returns false if stopped before the end.
+    async fn replay(&mut self) -> Result<bool> {
+        let mut stop = self.stop.clone();
+        let start = Instant::now();
+        let first = self
+            .events
+            .first()
+            .map(Event::timestamp)
+            .unwrap_or_default();
+        for mut e in std::mem::take(&mut self.events) {
+            let due = self.due(start, first, e.timestamp())?;
+            tokio::select! {
+                _ = time::sleep_until(due) => {}
+                _ = stop.stopped() => return Ok(false),
+            }
+            if self.cfg.rewrite_timestamps {
+                *e.timestamp_mut() = default_timestamp_ms();
+                // The parent has a new timestamp too, the database links it again.
+                if let Event::Message(msg) = &mut e {
+                    if let Some(reply) = &mut msg.reply_to {
+                        reply.timestamp = None;
+                    }
+                }
+            }
+            self.status.activity();
+            self.queue.publish(e).await?;
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Provider for ReplayClient {
+    type Config = Replay;
+
+    async fn new(cfg: &Replay) -> Result<Self> {
+        let text = tokio::fs::read_to_string(&cfg.path)
+            .await
+            .with_context(|| format!("couldn't read {}", cfg.path))?;
+        let mut events = vec![];
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Event::from_json(line) {
+                Ok(e) => events.push(e),
+                Err(e) => warn!("{}:{}: skipping unreadable event: {:?}", cfg.path, n + 1, e),
+            }
+        }
+        // The checkpoint isn't in the order the events arrived.
+        events.sort_by_key(Event::timestamp);
+        let (stop_handle, stop) = stop_channel();
+        Ok(Self {
+            cfg: cfg.clone(),
+            events,
+            status: StatusHandle::new(),
+            queue: ProviderQueue::new("replay".to_owned()),
+            stop,
+            stop_handle,
+        })
+    }
+    fn subscribe(&self) -> QueueSubscriber {
+        self.queue.subscribe()
+    }
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+    fn set_status_handle(&mut self, status: StatusHandle) {
+        self.status = status;
+    }
+    fn set_queue_stats(&mut self, stats: QueueStats) {
+        self.queue.stats = stats;
+    }
+    async fn run(&mut self) -> Result<()> {
+        info!(
+            "replaying {} events of {} at {}x",
+            self.events.len(),
+            self.cfg.path,
+            self.cfg.speed
+        );
+        self.status.set_state(ProviderState::Ready);
+        let result = self.replay().await;
+        match &result {
+            Ok(true) => {
+                // Returning would make the supervisor start the replay again,
+                // so it waits to be stopped like a chat where nobody writes.
+                // It stays ready, with the activity a server ping would give,
+                // so /health doesn't report it as down or stale.
+                info!("replay of {} finished", self.cfg.path);
+                let mut keepalive = time::interval(Duration::from_secs(60));
+                let mut stop = self.stop.clone();
+                loop {
+                    tokio::select! {
+                        _ = keepalive.tick() => self.status.activity(),
+                        _ = stop.stopped() => break,
+                    }
+                }
+                self.status.set_state(ProviderState::Stopped);
+            }
+            Ok(false) => {
+                self.status.set_state(ProviderState::Stopped);
+            }
+            Err(e) => self.status.fail(format!("{:#}", e)),
+        }
+        // Ends the subscriber streams, so consumers know no more events will come.
+        self.queue.close();
+        result.map(|_| ())
+    }
+}
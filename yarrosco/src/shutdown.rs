@@ -0,0 +1,35 @@
+use anyhow::Result;
+use log::error;
+
+/// Exit code used when a second signal forces the process to end.
+const FORCED_EXIT_CODE: i32 = 130;
+
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM, returning its name.
+/// From then on, another signal exits the process right away.
+pub async fn wait_for_signal() -> Result<&'static str> {
+    let name = recv_signal().await?;
+    tokio::spawn(async {
+        if let Ok(name) = recv_signal().await {
+            error!("{} received again, exiting without cleaning up", name);
+            std::process::exit(FORCED_EXIT_CODE);
+        }
+    });
+    Ok(name)
+}
+
+#[cfg(unix)]
+async fn recv_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let name = tokio::select! {
+        r = tokio::signal::ctrl_c() => r.map(|_| "SIGINT")?,
+        _ = terminate.recv() => "SIGTERM",
+    };
+    Ok(name)
+}
+
+#[cfg(not(unix))]
+async fn recv_signal() -> Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
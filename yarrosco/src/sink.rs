@@ -0,0 +1,328 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tokio::task::{self, JoinHandle};
+use yarrcfg::{Config, SinkKind};
+use yarrdata::db::{self, EventId};
+use yarrdata::router::{Filter, Router};
+use yarrdata::{Event, Message, QueueSubscriber, Received};
+
+/// Receives the events accepted into the database that match its filter.
+///
+/// Each sink runs in its own task with its own subscription, so a slow or
+/// failing sink doesn't hold back the others or the database.
+#[async_trait]
+pub trait OutputSink: Send {
+    async fn write(&mut self, event: &Event) -> Result<()>;
+    /// Called once before exiting, after the last event was written.
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Whether the events already in the database should be written at start-up.
+    fn wants_history(&self) -> bool {
+        false
+    }
+}
+
+pub fn new_sink(kind: &SinkKind) -> Box<dyn OutputSink> {
+    match kind {
+        SinkKind::Stdout => Box::new(StdoutSink),
+        SinkKind::Jsonl { path } => Box::new(JsonlSink::new(path.clone())),
+        SinkKind::ObsText { path, max_lines } => {
+            Box::new(ObsTextSink::new(path.clone(), *max_lines))
+        }
+    }
+}
+
+struct SinkHandle {
+    name: String,
+    task: JoinHandle<()>,
+}
+
+/// All the configured sinks.
+pub struct Sinks {
+    handles: Vec<SinkHandle>,
+}
+
+impl Sinks {
+    /// Subscribes every enabled sink to the router with its filter. The sinks
+    /// that want it get the matching messages of the database first.
+    pub async fn start(cfg: &Config, router: &Router, log: &Arc<Mutex<db::Log>>) -> Result<Self> {
+        let mut handles = vec![];
+        // Held while subscribing, like when publishing, so nothing is missed.
+        let locked = log.lock().await;
+        let last = locked.events_after(None).last().map(|(id, _)| id.clone());
+        for (name, sink_cfg) in cfg.sinks.iter() {
+            if !sink_cfg.enabled {
+                continue;
+            }
+            let filter = cfg.filter(sink_cfg.filter.as_deref())?;
+            let sink = new_sink(&sink_cfg.kind);
+            let past: Vec<Event> = match sink.wants_history() {
+                true => locked
+                    .data
+                    .values()
+                    .map(|ce| &ce.event)
+                    .filter(|ev| filter.matches(None, ev))
+                    .cloned()
+                    .collect(),
+                false => vec![],
+            };
+            let route = format!("sinks.{}", name);
+            let events = router.subscribe(&route, filter.clone());
+            let resync = Resync {
+                log: Arc::clone(log),
+                router: router.clone(),
+                route,
+                filter,
+                last: last.clone(),
+            };
+            let task = task::spawn(run_sink(name.clone(), sink, past, events, resync));
+            info!("started output sink {:?} ({:?})", name, sink_cfg.kind);
+            handles.push(SinkHandle {
+                name: name.clone(),
+                task,
+            });
+        }
+        Ok(Self { handles })
+    }
+    /// Waits for every sink to write what it has received and close. The
+    /// router has to be closed first.
+    pub async fn close(self) {
+        for handle in self.handles {
+            if let Err(e) = handle.task.await {
+                error!("output sink {:?} task failed: {:?}", handle.name, e);
+            }
+        }
+    }
+}
+
+/// What a sink needs to catch up from the database after falling behind.
+struct Resync {
+    log: Arc<Mutex<db::Log>>,
+    router: Router,
+    route: String,
+    filter: Filter,
+    /// The last event the sink got.
+    last: Option<EventId>,
+}
+
+impl Resync {
+    /// Returns the stored events after the last one received, and a new
+    /// subscription to those that come afterwards. Both are taken under the
+    /// database lock, which is also held while publishing, so nothing is lost
+    /// or written twice.
+    async fn catch_up(&mut self) -> (Vec<Arc<Event>>, QueueSubscriber) {
+        let log = self.log.lock().await;
+        let mut missed = vec![];
+        for (id, ce) in log.events_after(self.last.as_ref()) {
+            if self.filter.matches(None, &ce.event) {
+                missed.push(Arc::new(ce.event.clone()));
+            }
+            self.last = Some(id.clone());
+        }
+        let events = self.router.subscribe(&self.route, self.filter.clone());
+        (missed, events)
+    }
+}
+
+async fn run_sink(
+    name: String,
+    mut sink: Box<dyn OutputSink>,
+    history: Vec<Event>,
+    mut events: QueueSubscriber,
+    mut resync: Resync,
+) {
+    for event in history.iter() {
+        if let Err(e) = sink.write(event).await {
+            error!("output sink {:?} couldn't write event: {:?}", name, e);
+        }
+    }
+    loop {
+        let batch = match events.recv().await {
+            Received::Event(event) => {
+                resync.last = Some(EventId::from_event(&event));
+                vec![event]
+            }
+            Received::Lagged(count) => {
+                warn!(
+                    "output sink {:?} is too slow, {} events behind, catching up from the database",
+                    name, count
+                );
+                let (missed, new_events) = resync.catch_up().await;
+                events = new_events;
+                missed
+            }
+            Received::Closed => break,
+        };
+        for event in batch.iter() {
+            if let Err(e) = sink.write(event).await {
+                error!("output sink {:?} couldn't write event: {:?}", name, e);
+            }
+        }
+    }
+    if let Err(e) = sink.close().await {
+        error!("output sink {:?} couldn't close: {:?}", name, e);
+    }
+}
+
+pub struct StdoutSink;
+
+#[async_trait]
+impl OutputSink for StdoutSink {
+    async fn write(&mut self, event: &Event) -> Result<()> {
+        match event {
+            Event::Message(m) => {
+                println!("#{}::{}> {}", m.provider_name, m.username, m.message)
+            }
+            Event::MessageEdited(e) => {
+                println!(
+                    "#{}::{}> (edited) {}",
+                    e.provider_name, e.username, e.message
+                )
+            }
+            Event::MessageDeleted(d) => {
+                println!("#{}::{}> (message deleted)", d.provider_name, d.username)
+            }
+            Event::UserPurged(p) => {
+                println!("#{}::{}> (messages removed)", p.provider_name, p.username)
+            }
+            Event::ChatCleared(c) => println!("#{}> (chat cleared)", c.provider_name),
+            Event::Reaction(r) => {
+                println!("#{}::{}> (reacted {})", r.provider_name, r.username, r.key)
+            }
+            Event::Subscription(_)
+            | Event::SubGift(_)
+            | Event::Raid(_)
+            | Event::Announcement(_) => {
+                println!("#{}> {}", event.provider_name(), notice_text(event))
+            }
+        }
+        Ok(())
+    }
+    fn wants_history(&self) -> bool {
+        true
+    }
+}
+
+pub struct JsonlSink {
+    path: String,
+    writer: Option<BufWriter<File>>,
+}
+
+impl JsonlSink {
+    pub fn new(path: String) -> Self {
+        Self { path, writer: None }
+    }
+    async fn get_writer(&mut self) -> Result<&mut BufWriter<File>> {
+        if self.writer.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            self.writer = Some(BufWriter::new(file));
+        }
+        Ok(self.writer.as_mut().unwrap())
+    }
+}
+
+#[async_trait]
+impl OutputSink for JsonlSink {
+    async fn write(&mut self, event: &Event) -> Result<()> {
+        let json = event.to_json()?;
+        let writer = self.get_writer().await?;
+        writer.write_all(json.as_bytes()).await?;
+        // So the file can be followed while it's being written.
+        writer.flush().await?;
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a plain text file with the last lines of chat for an OBS text source.
+pub struct ObsTextSink {
+    path: String,
+    max_lines: usize,
+    /// The lines shown, with the message they come from to take them out
+    /// again if it gets removed.
+    lines: VecDeque<(Option<Message>, String)>,
+}
+
+impl ObsTextSink {
+    pub fn new(path: String, max_lines: usize) -> Self {
+        Self {
+            path,
+            max_lines,
+            lines: VecDeque::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl OutputSink for ObsTextSink {
+    async fn write(&mut self, event: &Event) -> Result<()> {
+        match event {
+            Event::Message(m) => {
+                let line = format!("{}: {}", m.username, m.message);
+                self.lines.push_back((Some(m.clone()), line));
+            }
+            Event::MessageEdited(e) => {
+                for (m, line) in self.lines.iter_mut() {
+                    if let Some(m) = m.as_ref().filter(|m| e.edits(m)) {
+                        *line = format!("{}: {}", m.username, e.message);
+                    }
+                }
+            }
+            Event::Subscription(_)
+            | Event::SubGift(_)
+            | Event::Raid(_)
+            | Event::Announcement(_) => self.lines.push_back((None, notice_text(event))),
+            // Plain text has nowhere to put them.
+            Event::Reaction(_) => {}
+            removal => self
+                .lines
+                .retain(|(m, _)| !matches!(m, Some(m) if removal.removes(m))),
+        }
+        while self.lines.len() > self.max_lines {
+            self.lines.pop_front();
+        }
+        let mut text = String::new();
+        for (_, line) in self.lines.iter() {
+            text.push_str(line);
+            text.push('\n');
+        }
+        // Replace the file in one go, so OBS never reads it half written.
+        let tmp_path = format!("{}.tmp", self.path);
+        fs::write(&tmp_path, text).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+    fn wants_history(&self) -> bool {
+        true
+    }
+}
+
+/// One line of text for subscriptions, gifts, raids and announcements.
+fn notice_text(event: &Event) -> String {
+    match event {
+        Event::Subscription(n) if !n.message.is_empty() => {
+            format!("{} {}: {}", n.system_message, n.username, n.message)
+        }
+        Event::Subscription(n) => n.system_message.clone(),
+        Event::SubGift(n) => n.system_message.clone(),
+        Event::Raid(n) => n.system_message.clone(),
+        Event::Announcement(n) => format!("{}: {}", n.username, n.message),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use log::{error, info, warn};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use yarrdata::status::ProviderState;
+use yarrdata::{QueueStats, QueueSubscriber, Received, StopHandle, StopSignal};
+
+use crate::health::ProviderHandles;
+use crate::ingest::Ingest;
+use crate::providers::ProviderKey;
+
+/// A provider client that was just created, with its `run` already spawned.
+pub struct Started {
+    pub stop: StopHandle,
+    pub sub: QueueSubscriber,
+    pub run: JoinHandle<Result<()>>,
+}
+
+/// Creates and starts a new client for a provider, called on every (re)start.
+/// The client must report through the given handles.
+pub type StartFn =
+    Box<dyn Fn(ProviderHandles) -> BoxFuture<'static, Result<Started>> + Send + Sync>;
+
+/// Jittered exponential backoff between restarts.
+#[derive(Debug, Default)]
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    const BASE: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(300);
+    /// A provider that ran for this long is considered healthy again.
+    const STABLE_AFTER: Duration = Duration::from_secs(120);
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = Self::BASE
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(Self::MAX);
+        self.attempt += 1;
+        // Anywhere between half and the full delay, so providers that failed
+        // at the same time (e.g. the network went down) don't retry in sync.
+        let ms = delay.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(ms / 2..=ms))
+    }
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Keeps a provider running until `stop` is signaled, restarting it whenever
+/// it ends, fails or panics. Returns the result of the last run.
+pub async fn supervise(
+    key: ProviderKey,
+    ingest: Arc<Ingest>,
+    mut stop: StopSignal,
+    handles: ProviderHandles,
+    start: StartFn,
+) -> Result<()> {
+    let status = &handles.status;
+    let mut backoff = Backoff::default();
+    loop {
+        info!("provider {}: starting", key);
+        let started_at = Instant::now();
+        let result = match start(handles.clone()).await {
+            Ok(started) => {
+                info!("provider {}: running", key);
+                let result = run_until_end(&key, &ingest, &mut stop, &handles.queue, started).await;
+                handles.queue.clear();
+                result
+            }
+            Err(e) => Err(e.context("couldn't start the client")),
+        };
+        if stop.is_stopped() {
+            info!("provider {}: stopped", key);
+            status.set_state(ProviderState::Stopped);
+            return result;
+        }
+        match &result {
+            Ok(()) => {
+                warn!("provider {}: ended unexpectedly", key);
+                // Keep the reason if the client already reported one.
+                if status.state() != ProviderState::Failed {
+                    status.fail("ended unexpectedly");
+                }
+            }
+            Err(e) => {
+                error!("provider {}: failed: {:?}", key, e);
+                status.fail(format!("{:#}", e));
+            }
+        }
+        if started_at.elapsed() >= Backoff::STABLE_AFTER {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        info!(
+            "provider {}: restarting in {:.1}s (attempt {})",
+            key,
+            delay.as_secs_f32(),
+            backoff.attempt
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = stop.stopped() => {
+                info!("provider {}: stopped while waiting to restart", key);
+                status.set_state(ProviderState::Stopped);
+                return result;
+            }
+        }
+    }
+}
+
+/// Forwards the events of a running client until it ends, asking it to stop
+/// if `stop` is signaled.
+async fn run_until_end(
+    key: &ProviderKey,
+    ingest: &Ingest,
+    stop: &mut StopSignal,
+    queue: &QueueStats,
+    started: Started,
+) -> Result<()> {
+    /// How long to wait for the queue to close after the client ended.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+    let Started {
+        stop: client_stop,
+        sub,
+        mut run,
+    } = started;
+    // Providers close their queue when they stop, so once the subscription
+    // ends every in-flight event has already been pushed into the database.
+    let forward = sub
+        .into_stream()
+        .for_each_concurrent(2, |received| async move {
+            match received {
+                Received::Event(event) => {
+                    queue.consumed();
+                    ingest.process(key, event).await;
+                }
+                // Provider queues wait for us instead of skipping events.
+                Received::Lagged(_) | Received::Closed => {}
+            }
+        });
+    tokio::pin!(forward);
+    let mut stopping = false;
+    let (result, forwarded) = loop {
+        tokio::select! {
+            result = &mut run => break (result, false),
+            () = &mut forward => break ((&mut run).await, true),
+            _ = stop.stopped(), if !stopping => {
+                stopping = true;
+                client_stop.stop();
+            }
+        }
+    };
+    // A client that panicked might not have closed its queue.
+    if !forwarded && time::timeout(DRAIN_TIMEOUT, forward).await.is_err() {
+        warn!("provider {}: queue not closed, pending events dropped", key);
+    }
+    match result {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => Err(anyhow!("client panicked")),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,53 @@
+#![cfg(test)]
+use yarrcfg::Demo;
+use yarrdata::provider::Provider;
+
+use crate::demo::DemoClient;
+
+async fn demo(seed: u64) -> DemoClient {
+    let cfg = Demo {
+        rate: 1.0,
+        seed: Some(seed),
+        room: "#demo".to_owned(),
+        provider_name: "demo".to_owned(),
+    };
+    DemoClient::new(&cfg).await.unwrap()
+}
+
+#[tokio::test]
+async fn demo_emotes_are_at_their_positions() {
+    let mut client = demo(7).await;
+    let mut unicode = 0;
+    for _ in 0..500 {
+        let (text, emotes) = client.text();
+        if !emotes.is_empty() && !text.is_ascii() {
+            unicode += 1;
+        }
+        // Positions are in characters, with `to` included.
+        for emote in emotes {
+            let found: String = text
+                .chars()
+                .skip(emote.from)
+                .take(emote.to + 1 - emote.from)
+                .collect();
+            assert_eq!(found, emote.name, "in {:?}", text);
+        }
+    }
+    // Emotes after multibyte characters are the ones that can go wrong.
+    assert!(unicode > 0);
+}
+
+#[tokio::test]
+async fn demo_sessions_repeat_with_the_same_seed() {
+    let (mut a, mut b) = (demo(42).await, demo(42).await);
+    for _ in 0..200 {
+        let (ea, eb) = (a.event(), b.event());
+        assert_eq!(ea.kind(), eb.kind());
+        assert_eq!(ea.username(), eb.username());
+        assert_eq!(ea.text(), eb.text());
+    }
+    let mut other = demo(43).await;
+    let texts =
+        |client: &mut DemoClient| -> Vec<String> { (0..20).map(|_| client.text().0).collect() };
+    assert_ne!(texts(&mut a), texts(&mut other));
+}
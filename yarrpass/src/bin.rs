@@ -1,14 +1,26 @@
 use anyhow::{Context, Result};
+use clap::Parser;
 
 use yarrpass::SaltAndCipher;
 use yarrpass::{get_password_str, password};
 
+/// Encodes secrets for yarrsecrets.toml using the passphrase in
+/// YARROSCO_PASSPHRASE (or asked for).
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    /// Token to decode. Without it, a new secret is asked for and encoded
+    token: Option<String>,
+    /// Log level (error, warn, info, debug, trace). Overrides RUST_LOG
+    #[clap(long)]
+    log_level: Option<String>,
+}
+
 fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    yarrcommon::set_log_level(args.log_level.as_deref());
     env_logger::init();
-    match std::env::args().nth(1) {
+    match args.token {
         Some(c) => decode(c).context("Decode step failed")?,
         None => encode().context("Encode step failed")?,
     }
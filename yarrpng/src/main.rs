@@ -1,20 +1,40 @@
 use std::fs::File;
 use std::io::Write;
+use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+use clap::Parser;
 use headless_chrome::protocol::cdp::Page::CaptureScreenshotFormatOption;
 use headless_chrome::Browser;
 
+/// Takes a screenshot of the overlay using a headless Chrome.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    /// Page to capture
+    #[clap(long, default_value = "http://localhost:8080/yarrosco_chat.html")]
+    url: String,
+    /// PNG file to write
+    #[clap(long, short, default_value = "yarrosco_chat.png")]
+    output: PathBuf,
+    /// Log level (error, warn, info, debug, trace). Overrides RUST_LOG
+    #[clap(long)]
+    log_level: Option<String>,
+}
+
 fn main() -> anyhow::Result<()> {
-    browse()
+    let args = Args::parse();
+    yarrcommon::set_log_level(args.log_level.as_deref());
+    env_logger::init();
+    browse(&args)
 }
 
-fn browse() -> anyhow::Result<()> {
+fn browse(args: &Args) -> anyhow::Result<()> {
     let browser = Browser::default()?;
 
     let tab = browser.wait_for_initial_tab()?;
-    tab.navigate_to("http://localhost/yarrosco/yarrosco_chat.html")?;
+    tab.navigate_to(&args.url)?;
     let _content = tab.wait_for_element("#content")?;
     sleep(Duration::from_secs_f32(0.5));
     let now = Instant::now();
@@ -30,7 +50,7 @@ fn browse() -> anyhow::Result<()> {
         tab.capture_screenshot(CaptureScreenshotFormatOption::Png, Some(0), None, true)?;
     dbg!(now.elapsed());
 
-    let mut file = File::create("test.png")?;
+    let mut file = File::create(&args.output)?;
     file.write_all(&png_data)?;
     dbg!(now.elapsed());
     Ok(())
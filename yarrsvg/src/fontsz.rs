@@ -81,31 +81,12 @@ impl Font {
         }
         total_width
     }
-    pub fn split_lines(&self, text: &str, maxwidth: f64) -> Vec<String> {
-        let mut ret: Vec<String> = vec![];
+    /// Horizontal advance of a character, or `None` if the font doesn't have it.
+    pub fn glyph_width(&self, ch: char) -> Option<f64> {
         let uem = self.face.units_per_em();
-        let mut xpos = 0.0;
-        let mut line = String::new();
-
-        for ch in text.chars() {
-            if let Some(glyph) = self.face.glyph_index(ch) {
-                if let Some(width) = self.face.glyph_hor_advance(glyph) {
-                    let width: f64 = width as f64 / uem as f64 * self.size;
-                    if xpos + width < maxwidth {
-                        line.push(ch);
-                        xpos += width;
-                    } else {
-                        ret.push(line.clone());
-                        line.clear();
-                        line.push(ch);
-                        xpos = width;
-                    }
-                }
-            }
-        }
-        ret.push(line);
-
-        ret
+        let glyph = self.face.glyph_index(ch)?;
+        let width = self.face.glyph_hor_advance(glyph)?;
+        Some(width as f64 / uem as f64 * self.size)
     }
 }
 
@@ -0,0 +1,79 @@
+use crate::fontsz::Font;
+use yarrdata::Fragment;
+
+/// Size of emotes and cheermotes, relative to the font size.
+pub const IMAGE_SCALE: f64 = 1.2;
+
+/// A piece of a line, `x` pixels from the start of the line.
+pub enum Span {
+    Text { x: f64, text: String },
+    Image { x: f64, url: String },
+}
+
+/// Wraps the fragments of a message into lines no wider than `maxwidth`.
+/// Text can be cut at any character; emotes and cheermotes are drawn as
+/// images and never cut.
+pub fn split_lines(font: &Font, fragments: &[Fragment], maxwidth: f64) -> Vec<Vec<Span>> {
+    let mut lines = Lines {
+        font,
+        maxwidth,
+        done: vec![],
+        line: vec![],
+        xpos: 0.0,
+    };
+    for fragment in fragments {
+        match fragment {
+            Fragment::Emote { url, .. } => lines.push_image(url),
+            Fragment::Cheermote { url, bits, .. } => {
+                lines.push_image(url);
+                lines.push_text(&bits.to_string());
+            }
+            other => lines.push_text(other.text()),
+        }
+    }
+    lines.done.push(lines.line);
+    lines.done
+}
+
+struct Lines<'a> {
+    font: &'a Font,
+    maxwidth: f64,
+    done: Vec<Vec<Span>>,
+    line: Vec<Span>,
+    xpos: f64,
+}
+
+impl<'a> Lines<'a> {
+    fn make_room(&mut self, width: f64) {
+        if self.xpos + width >= self.maxwidth && !self.line.is_empty() {
+            self.done.push(std::mem::take(&mut self.line));
+            self.xpos = 0.0;
+        }
+    }
+    fn push_image(&mut self, url: &str) {
+        let width = self.font.size * IMAGE_SCALE;
+        self.make_room(width);
+        self.line.push(Span::Image {
+            x: self.xpos,
+            url: url.to_owned(),
+        });
+        self.xpos += width;
+    }
+    fn push_text(&mut self, text: &str) {
+        for ch in text.chars() {
+            let width = match self.font.glyph_width(ch) {
+                Some(width) => width,
+                None => continue,
+            };
+            self.make_room(width);
+            match self.line.last_mut() {
+                Some(Span::Text { text, .. }) => text.push(ch),
+                _ => self.line.push(Span::Text {
+                    x: self.xpos,
+                    text: ch.to_string(),
+                }),
+            }
+            self.xpos += width;
+        }
+    }
+}
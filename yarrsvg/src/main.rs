@@ -1,40 +1,64 @@
 mod fontsz;
+mod layout;
+use std::path::PathBuf;
 use std::time::Instant;
 
 use anyhow::Result;
+use clap::Parser;
+use log::debug;
 use log::error;
 use log::info;
 use log::LevelFilter;
 use svg::node;
 // use svg::node::element;
+use layout::Span;
 use svg::node::element::Definitions;
 use svg::node::element::Element;
 use svg::node::element::Filter;
 use svg::node::element::Group;
 use svg::Document;
 use svg::Node;
+use yarrcfg::{set_log_level, ConfigArgs, ConfigLoader};
 use yarrdata::db;
-
-const OUT_FILENAME: &str = "./yarrosco_chat.svg";
+use yarrdata::Fragment;
+
+/// Renders the last messages of the database into an SVG image.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    cfg: ConfigArgs,
+    /// SVG file to write
+    #[clap(long, short, default_value = "yarrosco_chat.svg")]
+    output: PathBuf,
+    /// Only render the events matching this filter from the config's [filters]
+    #[clap(long)]
+    filter: Option<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    set_log_level(args.cfg.log_level.as_deref());
     env_logger::builder()
         .filter(Some("sled"), LevelFilter::Info)
         .filter(Some("reqwest"), LevelFilter::Info)
         .init();
 
-    let cfg = yarrcfg::parse_config()?;
+    let cfg = ConfigLoader::from_args(&args.cfg).load()?;
+    let filter = cfg.filter(args.filter.as_deref())?;
 
     // Read from database
     let mut log = db::Log::new(100, cfg.logfile, cfg.checkpointfile);
     if let Err(e) = log.load().await {
         error!("couldn't load the database: {:?}", e);
     }
-    let data: Vec<yarrdata::Event> = log.data.values().map(|ce| ce.event.clone()).collect();
+    let data: Vec<yarrdata::Event> = log
+        .data
+        .values()
+        .filter(|ce| filter.matches(None, &ce.event))
+        .map(|ce| ce.event.clone())
+        .collect();
 
     // let data = element::path::Data::new()
     //     .move_to((10, 10))
@@ -129,53 +153,39 @@ async fn main() -> Result<()> {
     for e in data.iter().rev() {
         match e {
             yarrdata::Event::Message(m) => {
-                // TODO: This doesn't wrap lines - we need to compute the width
-                let text = format!("{}: {}", m.username, m.message);
                 let y = doc_height as f64 - (n * dejavu_face.size * 1.2) - margin;
                 if y < 0.0 {
                     break;
                 }
 
-                let lines = dejavu_face.split_lines(&text, doc_width as f64);
-                for text in lines.iter().rev() {
+                let mut fragments = vec![Fragment::Text {
+                    text: format!("{}: ", m.username),
+                }];
+                fragments.extend(m.fragments.iter().cloned());
+                let lines = layout::split_lines(&dejavu_face, &fragments, doc_width as f64);
+                for line in lines.iter().rev() {
                     let y = doc_height as f64 - (n * dejavu_face.size * 1.2) - margin;
                     n += 1.0;
-                    let width: Vec<f64> = dejavu_face.char_width(text);
-                    let sumwidth: f64 = width.iter().sum();
-                    info!("w: {:.2}px, t: {}", sumwidth, &text);
-                    let text = create_text(margin, y, text);
-                    // let mut xpos: f64 = 5.0;
-                    // for w in width.iter().copied() {
-                    //     let data = element::path::Data::new()
-                    //         .move_to((xpos, y + 1.0))
-                    //         .line_by((w, 1.0))
-                    //         .close();
-                    //     let path = node::element::Path::new()
-                    //         .set("fill", "none")
-                    //         .set("stroke", "black")
-                    //         .set("stroke-width", 0.5)
-                    //         .set("d", data);
-                    //     document = document.add(path);
-                    //     xpos += w;
-                    // }
-
-                    // let data = element::path::Data::new()
-                    //     .move_to((5.0 + sumwidth, y))
-                    //     .line_by((0.0, -szpx))
-                    //     .close();
-
-                    // let path = node::element::Path::new()
-                    //     .set("fill", "none")
-                    //     .set("stroke", "black")
-                    //     .set("stroke-width", 1)
-                    //     .set("d", data);
-                    // document = document.add(path);
-                    main_group = main_group.add(text);
+                    for span in line {
+                        match span {
+                            Span::Text { x, text } => {
+                                debug!("x: {:.2}px, t: {}", x, text);
+                                main_group = main_group.add(create_text(margin + x, y, text));
+                            }
+                            Span::Image { x, url } => {
+                                let size = dejavu_face.size * layout::IMAGE_SCALE;
+                                main_group = main_group.add(create_image(margin + x, y, size, url));
+                            }
+                        }
+                    }
                 }
                 n += 0.5;
 
                 count += 1;
             }
+            // Removed messages are already gone from the database, and the
+            // rest of events are not shown here.
+            _ => {}
         }
     }
     document = document.add(main_group);
@@ -183,7 +193,7 @@ async fn main() -> Result<()> {
     info!("Craft: {:?} Count: {}", now.elapsed(), count);
     let now = Instant::now();
 
-    svg::save(OUT_FILENAME, &document).unwrap();
+    svg::save(&args.output, &document).unwrap();
     info!("Save: {:?}", now.elapsed());
 
     // let svgstr = document.to_string();
@@ -220,3 +230,14 @@ fn create_text(x: f64, y: f64, text: &str) -> node::element::Text {
     .set("id", id)
     .add(node::Text::new(text))
 }
+
+/// An emote or cheermote on a line of text with its baseline at `y`.
+fn create_image(x: f64, y: f64, size: f64, url: &str) -> node::element::Image {
+    node::element::Image::new()
+        .set("x", format!("{:.2}", x))
+        // Images are placed by their top, text by its baseline.
+        .set("y", format!("{:.2}", y - size * 0.8))
+        .set("width", format!("{:.2}", size))
+        .set("height", format!("{:.2}", size))
+        .set("href", url)
+}
@@ -1,26 +1,58 @@
-use anyhow::{Ok, Result};
-use futures::prelude::*;
+use anyhow::{anyhow, Context, Ok, Result};
+use clap::Parser;
 use std::borrow::Borrow;
 use twitch_api2::helix::{self, chat::get_global_chat_badges};
 use twitch_api2::TwitchClient;
 use twitch_oauth2::tokens::UserToken;
 use twitch_oauth2::types::AccessToken;
-use yarrcfg::parse_config;
-use yarrdata::Event;
+use yarrcfg::{set_log_level, ConfigArgs, ConfigLoader};
+use yarrdata::provider::Provider;
+use yarrdata::{Event, Received};
+
+/// Connects to Twitch with the config of yarrosco and prints the messages received.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    cfg: ConfigArgs,
+    /// Which [twitch.NAME] section to use [default: the first one]
+    #[clap(long)]
+    name: Option<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "INFO");
-    }
+    let args = Args::parse();
+    set_log_level(args.cfg.log_level.as_deref());
     env_logger::init();
 
-    let cfg = parse_config()?;
-    let (_, twitch_cfg) = cfg
-        .twitch
-        .iter()
-        .next()
-        .expect("Twitch config is needed to run IRC");
+    let cfg = ConfigLoader::from_args(&args.cfg).load()?;
+    let twitch_cfg = match &args.name {
+        Some(name) => cfg.twitch.get(name).ok_or_else(|| {
+            let sections: Vec<String> = cfg
+                .twitch
+                .keys()
+                .map(|n| format!("[twitch.{}]", n))
+                .collect();
+            if sections.is_empty() {
+                anyhow!(
+                    "there's no [twitch.{}] in the config, nor any other [twitch.NAME]",
+                    name
+                )
+            } else {
+                anyhow!(
+                    "there's no [twitch.{}] in the config, only {}",
+                    name,
+                    sections.join(", ")
+                )
+            }
+        })?,
+        None => cfg
+            .twitch
+            .values()
+            .next()
+            .context("a [twitch.NAME] section is needed to run")?,
+    };
     // -----
 
     let client: TwitchClient<'static, reqwest::Client> = TwitchClient::default();
@@ -36,12 +68,18 @@ async fn main() -> Result<()> {
     let mut twitch_sub = tw.subscribe();
     let tw_future = tokio::task::spawn(async move { tw.run().await });
 
-    while let Some(ev) = twitch_sub.next().await {
+    loop {
+        let ev = match twitch_sub.recv().await {
+            Received::Event(ev) => ev,
+            // Provider queues wait for the subscriber instead of skipping events.
+            Received::Lagged(_) | Received::Closed => break,
+        };
         // Upon receiving a new message...
         match ev.borrow() {
             Event::Message(m) => {
                 println!(">> {:?}>> {}", std::thread::current().id(), m.message)
             }
+            ev => println!(">> {:?}>> {:?}", std::thread::current().id(), ev),
         }
     }
     tw_future.await??;
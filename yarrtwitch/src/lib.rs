@@ -1,23 +1,30 @@
 use anyhow::Result;
-use bus_queue::Subscriber;
+use async_trait::async_trait;
 use futures::StreamExt;
 use irc::client::prelude::*;
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::time::Duration;
 use std::vec;
 use thiserror::Error;
 use twitch_api2::helix;
+use twitch_api2::helix::bits::get_cheermotes;
 use twitch_api2::helix::chat::get_channel_chat_badges;
 use twitch_api2::helix::chat::get_channel_emotes;
 use twitch_api2::helix::chat::get_emote_sets;
 use twitch_api2::helix::chat::get_global_chat_badges;
 use twitch_api2::helix::chat::get_global_emotes;
+use twitch_api2::types;
 use twitch_api2::TwitchClient as ApiTwitchClient;
 use twitch_oauth2::tokens::UserToken;
 use twitch_oauth2::types::AccessToken;
 use yarrcfg::Twitch;
-use yarrdata::{Badge, Event, ProviderQueue, SyncSubscriber};
+use yarrdata::provider::Provider;
+use yarrdata::status::{ProviderState, StatusHandle};
+use yarrdata::{
+    stop_channel, Author, Badge, ChatCleared, Event, MessageDeleted, ProviderQueue, QueueStats,
+    QueueSubscriber, ReplyTo, Role, StopHandle, StopSignal, SubTier, UserPurged,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -62,37 +69,18 @@ pub struct TwitchClient {
     config: irc::client::data::config::Config,
     user_token: UserToken,
     extensions: Vec<Capability>,
-    ready: bool,
+    status: StatusHandle,
     queue: ProviderQueue,
     badges: Vec<helix::chat::BadgeSet>,
     emotes: HashMap<String, Emote>,
+    cheermotes: Vec<helix::bits::Cheermote>,
+    stop: StopSignal,
+    stop_handle: StopHandle,
 }
 
 impl TwitchClient {
-    pub async fn new(twitch_cfg: &Twitch) -> Result<Self> {
-        let config = Config {
-            nickname: Some(twitch_cfg.username.clone()),
-            server: Some(twitch_cfg.server()),
-            port: twitch_cfg.port()?,
-            channels: twitch_cfg.channels.clone(),
-            password: Some(format!("oauth:{}", &twitch_cfg.oauth_token.0)),
-            ..Config::default()
-        };
-        let access_token = AccessToken::new(&twitch_cfg.oauth_token.0);
-        let client: ApiTwitchClient<'static, reqwest::Client> = ApiTwitchClient::default();
-        let user_token =
-            UserToken::from_existing(&client, access_token.clone(), None, None).await?;
-
-        Ok(Self {
-            config,
-            user_token,
-            extensions: vec![Capability::Custom(":twitch.tv/tags")],
-            ready: false,
-            queue: ProviderQueue::new("twitch".to_owned()),
-            badges: vec![],
-            emotes: HashMap::new(),
-        })
-    }
+    /// Time given to the server to acknowledge our QUIT before dropping the connection.
+    const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
     pub async fn get_global_emotes(&self) -> Result<Vec<helix::chat::GlobalEmote>> {
         // As it stands now, this is not used. Emotes are assumed to map to an URL.
         let client: ApiTwitchClient<'static, reqwest::Client> = ApiTwitchClient::default();
@@ -181,7 +169,7 @@ impl TwitchClient {
         Ok(emote)
     }
     fn emotes_from_str(&mut self, textemotes: &str, msg: &str) -> Vec<yarrdata::Emote> {
-        // .. Tag("emotes", Some("864205:17-24/444572:0-7/724216:9-15"))
+        // .. Tag("emotes", Some("864205:17-24,26-33/444572:0-7/724216:9-15"))
         let mut emotes: Vec<yarrdata::Emote> = vec![];
         for nb in textemotes.split('/') {
             if nb.is_empty() {
@@ -190,22 +178,30 @@ impl TwitchClient {
             let mut nbs = nb.split(':');
             let id = nbs.next().unwrap_or_default().to_string();
             let rangetxt = nbs.next().unwrap_or_default().to_string();
-            let mut range = rangetxt.split('-');
-            let from: usize = range.next().unwrap_or_default().parse().unwrap_or_default();
-            let to: usize = range.next().unwrap_or_default().parse().unwrap_or_default();
-            let name = msg[from..to + 1].to_owned();
             let url = format!(
                 "https://static-cdn.jtvnw.net/emoticons/v2/{}/static/light/2.0",
                 id
             );
-            let e = yarrdata::Emote {
-                id,
-                from,
-                to,
-                name,
-                url,
-            };
-            emotes.push(e);
+            // The same emote used several times comes with several ranges.
+            for rangetxt in rangetxt.split(',') {
+                let mut range = rangetxt.split('-');
+                let from: usize = range.next().unwrap_or_default().parse().unwrap_or_default();
+                let to: usize = range.next().unwrap_or_default().parse().unwrap_or_default();
+                // Twitch counts the positions in characters, not bytes.
+                let name: String = msg
+                    .chars()
+                    .skip(from)
+                    .take((to + 1).saturating_sub(from))
+                    .collect();
+                let e = yarrdata::Emote {
+                    id: id.clone(),
+                    from,
+                    to,
+                    name,
+                    url: url.clone(),
+                };
+                emotes.push(e);
+            }
             // TODO: Twitch doesn't return emotes for all valid IDs. Manually getting the URL works better :-(
             // match self.get_emote(&id) {
             //     Ok(dbemote) => {
@@ -267,23 +263,76 @@ impl TwitchClient {
         }
         badges
     }
-    pub fn subscribe(&self) -> Subscriber<Event> {
-        self.queue.subscribe()
+    /// Global cheermotes plus the ones of our channel.
+    pub async fn get_cheermotes(&self) -> Result<Vec<helix::bits::Cheermote>> {
+        let client: ApiTwitchClient<'static, reqwest::Client> = ApiTwitchClient::default();
+        let broadcaster_id = types::UserId::from(self.user_token.user_id.to_string());
+        let request = get_cheermotes::GetCheermotesRequest::builder()
+            .broadcaster_id(Some(broadcaster_id))
+            .build();
+        let response: Vec<helix::bits::Cheermote> =
+            client.helix.req_get(request, &self.user_token).await?.data;
+        debug!("Cheermotes: {:?}", response);
+        Ok(response)
     }
-    pub fn subscribe_sync(&self) -> SyncSubscriber {
-        self.queue.subscribe_sync()
+    /// Finds the cheers in a message: a cheermote prefix followed by the amount, like "Cheer100".
+    fn cheermotes_from_str(&self, msg: &str) -> Vec<yarrdata::Cheermote> {
+        let mut cheermotes = vec![];
+        // Positions are counted in characters, like Twitch does for emotes.
+        let mut pos = 0;
+        for word in msg.split(' ') {
+            let len = word.chars().count();
+            if let Some(mut cheermote) = self.find_cheermote(word) {
+                cheermote.from = pos;
+                cheermote.to = pos + len - 1;
+                cheermotes.push(cheermote);
+            }
+            pos += len + 1;
+        }
+        cheermotes
     }
-    pub async fn run(&mut self) -> Result<()> {
-        for _ in 0..16 {
-            if let Err(e) = self.run_once().await {
-                error!("On IRC connection: {:?}", e);
+    fn find_cheermote(&self, word: &str) -> Option<yarrdata::Cheermote> {
+        for cm in self.cheermotes.iter() {
+            let prefix = match word.get(..cm.prefix.len()) {
+                Some(prefix) if prefix.eq_ignore_ascii_case(&cm.prefix) => prefix,
+                _ => continue,
+            };
+            let amount = &word[prefix.len()..];
+            if amount.is_empty() || !amount.chars().all(|c| c.is_ascii_digit()) {
+                continue;
             }
+            // Too many digits for a u32; another cheermote may still match.
+            let bits: u32 = match amount.parse() {
+                Ok(bits) => bits,
+                Err(_) => continue,
+            };
+            // The tier is the highest one reached by the amount.
+            let tier = match cm
+                .tiers
+                .iter()
+                .filter(|t| t.min_bits <= i64::from(bits))
+                .max_by_key(|t| t.min_bits)
+            {
+                Some(tier) => tier,
+                None => continue,
+            };
+            return Some(yarrdata::Cheermote {
+                prefix: cm.prefix.clone(),
+                bits,
+                from: 0,
+                to: 0,
+                name: word.to_owned(),
+                url: tier.images.dark.animated.url_2x.clone(),
+                color: tier.color.clone(),
+            });
         }
-        warn!("end of connection retries");
-        Ok(())
+        None
+    }
+    pub fn status_handle(&self) -> StatusHandle {
+        self.status.clone()
     }
     async fn run_once(&mut self) -> Result<()> {
-        self.ready = false;
+        self.status.connecting();
         if self.badges.is_empty() {
             let badges = self.get_badges().await;
             match badges {
@@ -294,16 +343,41 @@ impl TwitchClient {
         if self.emotes.is_empty() {
             self.load_emotes().await;
         }
+        if self.cheermotes.is_empty() {
+            match self.get_cheermotes().await {
+                Ok(cheermotes) => self.cheermotes = cheermotes,
+                Err(e) => error!("trying to download cheermotes: {:?}", e),
+            }
+        }
         let mut client = Client::from_config(self.config.clone()).await?;
+        self.status.set_state(ProviderState::Authenticating);
         client.identify()?;
         client.send_cap_req(&self.extensions)?;
         let mut stream = client.stream()?;
+        let mut stop = self.stop.clone();
         // *** No question mark operator from here ---
         let mut err_count = 0;
-        while let Some(resmessage) = stream.next().await {
+        loop {
+            let resmessage = tokio::select! {
+                resmessage = stream.next() => match resmessage {
+                    Some(resmessage) => resmessage,
+                    None => break,
+                },
+                _ = stop.stopped() => {
+                    info!("Closing IRC connection.");
+                    if let Err(e) = client.send_quit("") {
+                        warn!("couldn't send QUIT to IRC server: {:?}", e);
+                    }
+                    // The stream has to be polled for the QUIT to be sent.
+                    let drain = async { while stream.next().await.is_some() {} };
+                    let _ = tokio::time::timeout(Self::QUIT_TIMEOUT, drain).await;
+                    break;
+                }
+            };
             match resmessage {
                 Ok(message) => {
-                    if let Err(e) = self.process_stream_sync(&message) {
+                    self.status.activity();
+                    if let Err(e) = self.process_stream(&message).await {
                         error!("error processing message {:?}: {:?}", &message, e);
                     } else {
                         err_count = 0;
@@ -311,6 +385,7 @@ impl TwitchClient {
                 }
                 Err(e) => {
                     error!("error while processing IRC stream: {:?}", e);
+                    self.status.error(&e);
                     err_count += 1;
                     if err_count > 10 {
                         error!("too many consecutive errors, giving up");
@@ -321,12 +396,11 @@ impl TwitchClient {
         }
         // *** to here --- (? operator)
         // Sometimes, specially when connecting, it kills the connection before auth.
-        warn!("IRC Connection ended (ready={:?})", self.ready);
-        self.ready = false;
+        warn!("IRC Connection ended (status={})", self.status.state());
         Ok(())
     }
 
-    fn process_stream_sync(&mut self, message: &Message) -> Result<()> {
+    async fn process_stream(&mut self, message: &Message) -> Result<()> {
         match &message.command {
             Command::PING(_, _) | Command::PONG(_, _) => {}
             Command::NOTICE(tgt, msg) => {
@@ -339,18 +413,27 @@ impl TwitchClient {
                         Response::RPL_MOTD | Response::RPL_MOTDSTART => {}
                         Response::RPL_ENDOFMOTD => {
                             info!("IRC Connection successful.");
-                            self.ready = true;
+                            self.status.set_state(ProviderState::Ready);
                         }
                         _ => debug!("< [{:?}] {:?}", r, data),
                     };
                 }
             },
-            Command::PRIVMSG(tgt, msg) => self.process_msg_sync(tgt, msg, message)?,
+            Command::PRIVMSG(tgt, msg) => self.process_msg(tgt, msg, message).await?,
+            Command::Raw(cmd, args) if cmd == "USERNOTICE" => {
+                self.process_usernotice(args, message).await?
+            }
+            Command::Raw(cmd, args) if cmd == "CLEARMSG" => {
+                self.process_clearmsg(args, message).await?
+            }
+            Command::Raw(cmd, args) if cmd == "CLEARCHAT" => {
+                self.process_clearchat(args, message).await?
+            }
             c => debug!(": {:?}", c),
         }
         Ok(())
     }
-    fn process_msg_sync(&mut self, target: &str, text: &str, message: &Message) -> Result<()> {
+    async fn process_msg(&mut self, target: &str, text: &str, message: &Message) -> Result<()> {
         use yarrdata::Message;
         let username = match message.prefix.as_ref().unwrap() {
             Prefix::ServerName(sn) => sn,
@@ -366,25 +449,23 @@ impl TwitchClient {
         );
         // .. Tag("subscriber", Some("0"))
         let mut msgid = String::new();
-        let mut timestamp: u64 = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let mut timestamp: u64 = yarrdata::default_timestamp_ms();
+        let author = author_from_tags(username, message);
         let mut username = username.clone();
 
         let mut badges: Vec<Badge> = vec![];
         let mut emotes: Vec<yarrdata::Emote> = vec![];
+        let mut bits: u32 = 0;
         if let Some(tags) = message.tags.as_ref() {
             for tag in tags {
                 if let Some(value) = &tag.1 {
                     match tag.0.as_str() {
                         "id" => msgid = value.to_owned(),
-                        "tmi-sent-ts" => {
-                            timestamp = value.parse().map_or(timestamp, |x: u64| x / 1000)
-                        }
+                        "tmi-sent-ts" => timestamp = value.parse().unwrap_or(timestamp),
                         "display-name" => username = value.to_owned(),
                         "badges" => badges = self.badges_from_str(value.as_str()),
                         "emotes" => emotes = self.emotes_from_str(value.as_str(), text),
+                        "bits" => bits = value.parse().unwrap_or_default(),
                         _ => {}
                     }
                 }
@@ -396,13 +477,331 @@ impl TwitchClient {
             room: target.to_owned(),
             message: text.to_owned(),
             username,
+            author,
             msgid,
             timestamp,
             badges,
             emotes,
+            bits,
+            cheermotes: match bits {
+                0 => vec![],
+                _ => self.cheermotes_from_str(text),
+            },
+            // Filled in when stored.
+            fragments: vec![],
+            reply_to: reply_from_tags(message).map(Box::new),
+        });
+        self.queue.publish(e).await?;
+        Ok(())
+    }
+    /// Subscriptions, gifts, raids and announcements.
+    /// `@msg-id=resub;msg-param-cumulative-months=6;... :tmi.twitch.tv USERNOTICE #channel :text`
+    async fn process_usernotice(&mut self, args: &[String], message: &Message) -> Result<()> {
+        use yarrdata::{Announcement, Raid, SubGift, Subscription};
+        debug!("USERNOTICE {:?} (tags: {:?})", args, message.tags);
+        let tags = Tags::new(message);
+        let provider_name = self.queue.provider_name.clone();
+        let room = args.first().cloned().unwrap_or_default();
+        let text = args.get(1).cloned().unwrap_or_default();
+        let username = tags
+            .get("display-name")
+            .filter(|name| !name.is_empty())
+            .or_else(|| tags.get("login"))
+            .unwrap_or_default()
+            .to_owned();
+        let msgid = tags.get("id").unwrap_or_default().to_owned();
+        let timestamp = tags.timestamp();
+        let system_message = tags.get("system-msg").unwrap_or_default().to_owned();
+        let badges = self.badges_from_str(tags.get("badges").unwrap_or_default());
+        let emotes = self.emotes_from_str(tags.get("emotes").unwrap_or_default(), &text);
+        let number = |name: &str| tags.get(name).and_then(|v| v.parse().ok());
+        let tier = match tags.get("msg-param-sub-plan") {
+            Some("Prime") => SubTier::Prime,
+            Some("2000") => SubTier::Tier2,
+            Some("3000") => SubTier::Tier3,
+            _ => SubTier::Tier1,
+        };
+        let e = match tags.get("msg-id").unwrap_or_default() {
+            "sub" | "resub" => Event::Subscription(Subscription {
+                provider_name,
+                room,
+                username,
+                msgid,
+                timestamp,
+                months: number("msg-param-cumulative-months").unwrap_or(1),
+                tier,
+                system_message,
+                message: text,
+                badges,
+                emotes,
+                fragments: vec![],
+            }),
+            "subgift" | "anonsubgift" => Event::SubGift(SubGift {
+                provider_name,
+                room,
+                username,
+                msgid,
+                timestamp,
+                recipient: tags
+                    .get("msg-param-recipient-display-name")
+                    .unwrap_or_default()
+                    .to_owned(),
+                count: 1,
+                tier,
+                system_message,
+            }),
+            "submysterygift" | "anonsubmysterygift" => Event::SubGift(SubGift {
+                provider_name,
+                room,
+                username,
+                msgid,
+                timestamp,
+                recipient: String::new(),
+                count: number("msg-param-mass-gift-count").unwrap_or(1),
+                tier,
+                system_message,
+            }),
+            "raid" => Event::Raid(Raid {
+                provider_name,
+                room,
+                username: tags
+                    .get("msg-param-displayName")
+                    .map_or(username, |name| name.to_owned()),
+                msgid,
+                timestamp,
+                viewers: number("msg-param-viewerCount").unwrap_or_default(),
+                system_message,
+            }),
+            "announcement" => Event::Announcement(Announcement {
+                provider_name,
+                room,
+                username,
+                msgid,
+                timestamp,
+                message: text,
+                color: tags.get("msg-param-color").unwrap_or_default().to_owned(),
+                badges,
+                emotes,
+                fragments: vec![],
+            }),
+            other => {
+                debug!("ignored USERNOTICE of type {:?}", other);
+                return Ok(());
+            }
+        };
+        self.queue.publish(e).await?;
+        Ok(())
+    }
+    /// A moderator deleted a single message.
+    /// `@login=user;target-msg-id=<id>;tmi-sent-ts=<ms> :tmi.twitch.tv CLEARMSG #channel :text`
+    async fn process_clearmsg(&mut self, args: &[String], message: &Message) -> Result<()> {
+        debug!("CLEARMSG {:?} (tags: {:?})", args, message.tags);
+        let tags = Tags::new(message);
+        let target_msgid = tags.get("target-msg-id").unwrap_or_default().to_owned();
+        if target_msgid.is_empty() {
+            warn!("CLEARMSG without target-msg-id: {:?}", message);
+            return Ok(());
+        }
+        let e = Event::MessageDeleted(MessageDeleted {
+            provider_name: self.queue.provider_name.clone(),
+            room: args.first().cloned().unwrap_or_default(),
+            username: tags.get("login").unwrap_or_default().to_owned(),
+            // Twitch gives no id to the deletion itself.
+            msgid: format!("clearmsg-{}", target_msgid),
+            target_msgid,
+            timestamp: tags.timestamp(),
         });
-        // This may block but the queue library promises non-blocking behavior.
-        self.queue.publish_sync(e)?;
+        self.queue.publish(e).await?;
+        Ok(())
+    }
+    /// A user was banned or timed out (`CLEARCHAT #channel :user`), or the
+    /// whole chat was cleared (`CLEARCHAT #channel`).
+    async fn process_clearchat(&mut self, args: &[String], message: &Message) -> Result<()> {
+        debug!("CLEARCHAT {:?} (tags: {:?})", args, message.tags);
+        let tags = Tags::new(message);
+        let provider_name = self.queue.provider_name.clone();
+        let room = args.first().cloned().unwrap_or_default();
+        let timestamp = tags.timestamp();
+        let e = match args.get(1) {
+            Some(username) => Event::UserPurged(UserPurged {
+                provider_name,
+                room,
+                username: username.to_owned(),
+                msgid: format!("clearchat-{}-{}", username, timestamp),
+                duration: tags.get("ban-duration").and_then(|d| d.parse().ok()),
+                timestamp,
+            }),
+            None => Event::ChatCleared(ChatCleared {
+                provider_name,
+                room,
+                msgid: format!("clearchat-{}", timestamp),
+                timestamp,
+            }),
+        };
+        self.queue.publish(e).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Provider for TwitchClient {
+    type Config = Twitch;
+
+    async fn new(twitch_cfg: &Twitch) -> Result<Self> {
+        let config = Config {
+            nickname: Some(twitch_cfg.username.clone()),
+            server: Some(twitch_cfg.server()),
+            port: twitch_cfg.port()?,
+            channels: twitch_cfg.channels.clone(),
+            password: Some(format!("oauth:{}", &twitch_cfg.oauth_token.0)),
+            ..Config::default()
+        };
+        let access_token = AccessToken::new(&twitch_cfg.oauth_token.0);
+        let client: ApiTwitchClient<'static, reqwest::Client> = ApiTwitchClient::default();
+        let user_token =
+            UserToken::from_existing(&client, access_token.clone(), None, None).await?;
+        let (stop_handle, stop) = stop_channel();
+
+        Ok(Self {
+            config,
+            user_token,
+            extensions: vec![
+                Capability::Custom(":twitch.tv/tags"),
+                // Needed to receive CLEARMSG and CLEARCHAT.
+                Capability::Custom("twitch.tv/commands"),
+            ],
+            status: StatusHandle::new(),
+            queue: ProviderQueue::new("twitch".to_owned()),
+            badges: vec![],
+            emotes: HashMap::new(),
+            cheermotes: vec![],
+            stop,
+            stop_handle,
+        })
+    }
+    fn subscribe(&self) -> QueueSubscriber {
+        self.queue.subscribe()
+    }
+    fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+    fn set_status_handle(&mut self, status: StatusHandle) {
+        self.status = status;
+    }
+    fn set_queue_stats(&mut self, stats: QueueStats) {
+        self.queue.stats = stats;
+    }
+    async fn run(&mut self) -> Result<()> {
+        for _ in 0..16 {
+            if self.stop.is_stopped() {
+                break;
+            }
+            if let Err(e) = self.run_once().await {
+                error!("On IRC connection: {:?}", e);
+                self.status.error(format!("{:#}", e));
+            }
+        }
+        if self.stop.is_stopped() {
+            self.status.set_state(ProviderState::Stopped);
+        } else {
+            warn!("end of connection retries");
+            self.status.fail("end of connection retries");
+        }
+        // Ends the subscriber streams, so consumers know no more events will come.
+        self.queue.close();
         Ok(())
     }
 }
+
+/// Who sent a message, from its tags. `login` is the nickname of the sender.
+fn author_from_tags(login: &str, message: &Message) -> Author {
+    let tags = Tags::new(message);
+    let badges = tags.get("badges").unwrap_or_default();
+    let has_badge = |name: &str| badges.split(',').any(|b| b.split('/').next() == Some(name));
+    let flag = |name: &str| tags.get(name) == Some("1");
+    let mut roles = vec![];
+    if has_badge("broadcaster") {
+        roles.push(Role::Broadcaster);
+    }
+    if flag("mod") || has_badge("moderator") {
+        roles.push(Role::Moderator);
+    }
+    if flag("vip") || has_badge("vip") {
+        roles.push(Role::Vip);
+    }
+    if flag("subscriber") || has_badge("founder") {
+        roles.push(Role::Subscriber);
+    }
+    Author {
+        id: tags.get("user-id").unwrap_or_default().to_owned(),
+        login: login.to_owned(),
+        display_name: tags
+            .get("display-name")
+            .filter(|name| !name.is_empty())
+            .unwrap_or(login)
+            .to_owned(),
+        color: tags.get("color").unwrap_or_default().to_owned(),
+        // Not sent in the chat, it would take a request to Helix for every user.
+        avatar_url: String::new(),
+        roles,
+    }
+}
+
+/// The message a PRIVMSG answers to, from its `reply-parent-*` tags.
+/// Replies to a reply also carry the first message of the thread.
+fn reply_from_tags(message: &Message) -> Option<ReplyTo> {
+    let tags = Tags::new(message);
+    let msgid = tags
+        .get("reply-parent-msg-id")
+        .filter(|id| !id.is_empty())?;
+    let login = tags.get("reply-parent-user-login").unwrap_or_default();
+    let thread_msgid = tags
+        .get("reply-thread-parent-msg-id")
+        .filter(|&id| id != msgid)
+        .unwrap_or_default();
+    Some(ReplyTo {
+        msgid: msgid.to_owned(),
+        // Twitch doesn't tell when the parent was sent; the database finds it.
+        timestamp: None,
+        author: Author {
+            id: tags
+                .get("reply-parent-user-id")
+                .unwrap_or_default()
+                .to_owned(),
+            login: login.to_owned(),
+            display_name: tags
+                .get("reply-parent-display-name")
+                .filter(|name| !name.is_empty())
+                .unwrap_or(login)
+                .to_owned(),
+            ..Default::default()
+        },
+        snippet: ReplyTo::snippet_of(tags.get("reply-parent-msg-body").unwrap_or_default()),
+        thread_msgid: thread_msgid.to_owned(),
+    })
+}
+
+/// The IRCv3 tags of a message that carry a value.
+struct Tags<'a> {
+    message: &'a Message,
+}
+
+impl<'a> Tags<'a> {
+    fn new(message: &'a Message) -> Self {
+        Self { message }
+    }
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.message
+            .tags
+            .as_ref()?
+            .iter()
+            .find(|tag| tag.0 == name)
+            .and_then(|tag| tag.1.as_deref())
+    }
+    /// `tmi-sent-ts` in milliseconds, or the current time if it's missing.
+    fn timestamp(&self) -> u64 {
+        self.get("tmi-sent-ts")
+            .and_then(|ts| ts.parse::<u64>().ok())
+            .unwrap_or_else(yarrdata::default_timestamp_ms)
+    }
+}